@@ -0,0 +1,2 @@
+ALTER TABLE user ADD COLUMN quota_bytes INTEGER; -- NULL means the server default
+ALTER TABLE file ADD COLUMN size INTEGER NOT NULL DEFAULT 0; -- filled in from disk by db.rs
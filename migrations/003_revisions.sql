@@ -0,0 +1,9 @@
+CREATE TABLE revision (
+    id INTEGER NOT NULL,
+    file_id INTEGER NOT NULL,
+    content BLOB NOT NULL,
+    size INTEGER NOT NULL,
+    created_at INTEGER DEFAULT (strftime('%s', 'now')), -- when this content was saved
+    PRIMARY KEY (id),
+    FOREIGN KEY(file_id) REFERENCES file (id)
+);
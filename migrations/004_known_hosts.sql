@@ -0,0 +1,7 @@
+CREATE TABLE known_host ( -- certificates pinned by the Gemini client
+    host TEXT NOT NULL, -- host:port
+    fingerprint TEXT NOT NULL, -- sha256 of the DER certificate
+    expires_at INTEGER NOT NULL,
+    first_seen_at INTEGER DEFAULT (strftime('%s', 'now')),
+    PRIMARY KEY (host)
+);
@@ -0,0 +1,9 @@
+CREATE TABLE proxy_cache ( -- 20 responses fetched by the HTTP proxy
+    url TEXT NOT NULL, -- as requested, before redirects
+    final_url TEXT NOT NULL,
+    meta TEXT NOT NULL,
+    body BLOB NOT NULL,
+    size INTEGER NOT NULL,
+    fetched_at INTEGER DEFAULT (strftime('%s', 'now')),
+    PRIMARY KEY (url)
+);
@@ -0,0 +1,9 @@
+CREATE TABLE session (
+    id TEXT NOT NULL, -- random, what the identity cookie holds
+    user_id INTEGER NOT NULL,
+    csrf_token TEXT NOT NULL,
+    created_at INTEGER DEFAULT (strftime('%s', 'now')),
+    expires_at INTEGER NOT NULL,
+    PRIMARY KEY (id),
+    FOREIGN KEY(user_id) REFERENCES user (id)
+);
@@ -0,0 +1,7 @@
+CREATE TABLE password_reset (
+    token_hash TEXT NOT NULL, -- sha256 of the token in the emailed link
+    user_id INTEGER NOT NULL,
+    expires_at INTEGER NOT NULL,
+    PRIMARY KEY (token_hash),
+    FOREIGN KEY(user_id) REFERENCES user (id)
+);
@@ -0,0 +1,3 @@
+ALTER TABLE user ADD COLUMN email_verified_at INTEGER; -- NULL until the emailed link is followed
+-- accounts from before verification existed keep publishing as they did
+UPDATE user SET email_verified_at = COALESCE(created_at, strftime('%s', 'now'));
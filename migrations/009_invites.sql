@@ -0,0 +1,12 @@
+ALTER TABLE user ADD COLUMN pending INTEGER NOT NULL DEFAULT 0; -- 1 while waiting for an admin, with registration = "approval"
+CREATE TABLE invite (
+    code TEXT NOT NULL,
+    created_by INTEGER, -- NULL for invites made by an admin
+    created_at INTEGER DEFAULT (strftime('%s', 'now')),
+    used_by INTEGER UNIQUE,
+    used_at INTEGER,
+    revoked_at INTEGER,
+    PRIMARY KEY (code),
+    FOREIGN KEY(created_by) REFERENCES user (id),
+    FOREIGN KEY(used_by) REFERENCES user (id)
+);
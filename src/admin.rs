@@ -1,5 +1,486 @@
-// Administrative actions
-//
-// Create user (gen pw) -- cli interface
-// Delete user
-// Reset user password
+/// Maintenance tasks for `flounder admin`, run against the same database as the server
+use crate::db;
+use crate::invite;
+use crate::password;
+use crate::proxy;
+use crate::session::{self, random_hex};
+use crate::utils::{rendered_size, username_errors};
+use crate::Config;
+use chrono::NaiveDateTime;
+use rusqlite::{params, Connection, OptionalExtension, NO_PARAMS};
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum AdminError {
+    Db(rusqlite::Error),
+    Migration(db::MigrationError),
+    Io(io::Error),
+    NoSuchUser(String),
+    UserExists(String),
+    NotPending(String),
+    Invalid(String),
+}
+
+impl fmt::Display for AdminError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AdminError::Db(e) => write!(f, "Database error: {}", e),
+            AdminError::Migration(e) => write!(f, "{}", e),
+            AdminError::Io(e) => write!(f, "{}", e),
+            AdminError::NoSuchUser(username) => write!(f, "No user named {}", username),
+            AdminError::UserExists(username) => write!(f, "{} is already taken", username),
+            AdminError::Invalid(message) => write!(f, "{}", message),
+            AdminError::NotPending(username) => {
+                write!(f, "{} is not waiting for approval", username)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AdminError {}
+
+impl From<rusqlite::Error> for AdminError {
+    fn from(e: rusqlite::Error) -> AdminError {
+        AdminError::Db(e)
+    }
+}
+
+impl From<db::MigrationError> for AdminError {
+    fn from(e: db::MigrationError) -> AdminError {
+        AdminError::Migration(e)
+    }
+}
+
+impl From<io::Error> for AdminError {
+    fn from(e: io::Error) -> AdminError {
+        AdminError::Io(e)
+    }
+}
+
+/// The server's database, brought up to date first in case the server hasn't run since an
+/// upgrade
+fn open(config: &Config) -> Result<Connection, AdminError> {
+    let mut conn = db::open(config)?;
+    db::migrate(&mut conn)?;
+    Ok(conn)
+}
+
+/// (id, pending) of the user
+fn find_user(conn: &Connection, username: &str) -> Result<(i64, bool), AdminError> {
+    conn.query_row(
+        "SELECT id, pending FROM user WHERE username = lower(?1)",
+        &[username],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )
+    .optional()?
+    .ok_or_else(|| AdminError::NoSuchUser(username.to_string()))
+}
+
+/// Removes the account, its files and their history. Invites it made and used stay behind,
+/// so the invite tree still shows who brought it in
+fn delete_account(
+    conn: &mut Connection,
+    config: &Config,
+    user_id: i64,
+    username: &str,
+) -> Result<(), AdminError> {
+    let tx = conn.transaction()?;
+    tx.execute(
+        "DELETE FROM revision WHERE file_id IN (SELECT id FROM file WHERE user_id = (?1))",
+        &[user_id],
+    )?;
+    tx.execute("DELETE FROM file WHERE user_id = (?1)", &[user_id])?;
+    tx.execute("DELETE FROM session WHERE user_id = (?1)", &[user_id])?;
+    tx.execute(
+        "DELETE FROM password_reset WHERE user_id = (?1)",
+        &[user_id],
+    )?;
+    tx.execute(
+        "DELETE FROM invite WHERE created_by = (?1) AND used_by IS NULL",
+        &[user_id],
+    )?;
+    tx.execute("DELETE FROM user WHERE id = (?1)", &[user_id])?;
+    tx.commit()?;
+    match std::fs::remove_dir_all(Path::new(&config.file_directory).join(username)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+fn generated_password() -> String {
+    random_hex(8)
+}
+
+fn check_username(username: &str) -> Result<(), AdminError> {
+    match username_errors(username).first() {
+        Some(error) => Err(AdminError::Invalid(format!("{}: {}", error, username))),
+        None => Ok(()),
+    }
+}
+
+fn username_taken(conn: &Connection, username: &str) -> Result<bool, AdminError> {
+    let found: Option<i64> = conn
+        .query_row(
+            "SELECT 1 FROM user WHERE username = (?1)",
+            &[username],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(found.is_some())
+}
+
+/// Returns how many cached pages were dropped
+pub fn purge_proxy_cache(config: &Config) -> Result<usize, AdminError> {
+    let conn = open(config)?;
+    Ok(proxy::purge_cache(&conn)?)
+}
+
+/// A password reset link for the user, for when mail isn't set up
+pub fn reset_link(config: &Config, username: &str) -> Result<String, AdminError> {
+    let conn = open(config)?;
+    let (user_id, _) = find_user(&conn, username)?;
+    let token = password::create_reset_token(&conn, user_id)?;
+    Ok(password::reset_link(config, &token))
+}
+
+/// A registration link with a fresh invite code that isn't anyone's
+pub fn create_invite(config: &Config) -> Result<String, AdminError> {
+    let conn = open(config)?;
+    let code = invite::create(&conn, None)?;
+    Ok(format!("{}/register?invite={}", config.base_url(), code))
+}
+
+/// Accounts waiting for approval, oldest first, as lines of username, email and sign-up time
+pub fn pending(config: &Config) -> Result<Vec<String>, AdminError> {
+    let conn = open(config)?;
+    let mut stmt = conn.prepare_cached(
+        r#"
+        SELECT username, email, created_at FROM user
+        WHERE pending = 1
+        ORDER BY created_at, id
+        "#,
+    )?;
+    let res = stmt.query_map(NO_PARAMS, |row| {
+        let username: String = row.get(0)?;
+        let email: Option<String> = row.get(1)?;
+        let created_at: i64 = row.get(2)?;
+        Ok(format!(
+            "{}\t{}\t{}",
+            username,
+            email.unwrap_or_default(),
+            NaiveDateTime::from_timestamp(created_at, 0).format("%Y-%m-%d %H:%M")
+        ))
+    })?;
+    Ok(res.collect::<Result<_, _>>()?)
+}
+
+pub fn approve(config: &Config, username: &str) -> Result<(), AdminError> {
+    let conn = open(config)?;
+    let (user_id, pending) = find_user(&conn, username)?;
+    if !pending {
+        return Err(AdminError::NotPending(username.to_string()));
+    }
+    conn.execute("UPDATE user SET pending = 0 WHERE id = (?1)", &[user_id])?;
+    crate::write_base_index(&conn, config, user_id, &username.to_lowercase())?;
+    Ok(())
+}
+
+/// Deletes a pending account. Approved accounts can't be removed this way
+pub fn reject(config: &Config, username: &str) -> Result<(), AdminError> {
+    let mut conn = open(config)?;
+    let (user_id, pending) = find_user(&conn, username)?;
+    if !pending {
+        return Err(AdminError::NotPending(username.to_string()));
+    }
+    delete_account(&mut conn, config, user_id, &username.to_lowercase())
+}
+
+/// Who invited the user, up to the first invite, then everyone below them, indented
+pub fn invite_tree(config: &Config, username: &str) -> Result<Vec<String>, AdminError> {
+    let conn = open(config)?;
+    let (user_id, _) = find_user(&conn, username)?;
+    let mut chain = invite::inviters(&conn, user_id)?;
+    chain.reverse();
+    chain.push(username.to_lowercase());
+    let mut lines = vec![chain.join(" -> ")];
+    for (depth, invitee) in invite::invitees(&conn, user_id)? {
+        lines.push(format!("{}{}", "  ".repeat(depth), invitee));
+    }
+    Ok(lines)
+}
+
+/// Makes an account the way registration would, minus approval and email verification,
+/// and returns its generated password
+pub fn create_user(
+    config: &Config,
+    username: &str,
+    email: Option<&str>,
+) -> Result<String, AdminError> {
+    let username = username.to_lowercase();
+    check_username(&username)?;
+    let mut conn = open(config)?;
+    if username_taken(&conn, &username)? {
+        return Err(AdminError::UserExists(username));
+    }
+    let password = generated_password();
+    let password_hash = bcrypt::hash(&password, bcrypt::DEFAULT_COST).unwrap();
+    let tx = conn.transaction()?;
+    tx.execute(
+        r#"
+        INSERT INTO user (username, email, password_hash, email_verified_at)
+        VALUES (?1, ?2, ?3, strftime('%s', 'now'))
+        "#,
+        params![username, email, password_hash],
+    )?;
+    let user_id = tx.last_insert_rowid();
+    tx.commit()?;
+    // after the commit, so a failed one doesn't leave a stray index.gmi behind
+    crate::write_base_index(&conn, config, user_id, &username)?;
+    Ok(password)
+}
+
+pub fn delete_user(config: &Config, username: &str) -> Result<(), AdminError> {
+    let mut conn = open(config)?;
+    let (user_id, _) = find_user(&conn, username)?;
+    delete_account(&mut conn, config, user_id, &username.to_lowercase())
+}
+
+/// Sets a generated password, logging the user out everywhere, and returns it
+pub fn reset_password(config: &Config, username: &str) -> Result<String, AdminError> {
+    let conn = open(config)?;
+    let (user_id, _) = find_user(&conn, username)?;
+    let password = generated_password();
+    password::set_password(&conn, &user_id.to_string(), &password)?;
+    Ok(password)
+}
+
+/// Every user as a line of username, email, space used, sign-up date and any of
+/// pending, locked or unverified (with verify_email on)
+pub fn list_users(config: &Config) -> Result<Vec<String>, AdminError> {
+    let conn = open(config)?;
+    let mut stmt = conn.prepare_cached(
+        r#"
+        SELECT user.username, user.email, COALESCE(SUM(file.size), 0), user.created_at,
+            user.pending, user.locked, user.email_verified_at IS NOT NULL
+        FROM user LEFT JOIN file
+        ON file.user_id = user.id
+        GROUP BY user.id
+        ORDER BY user.username
+        "#,
+    )?;
+    let res = stmt.query_map(NO_PARAMS, |row| {
+        let username: String = row.get(0)?;
+        let email: Option<String> = row.get(1)?;
+        let used: i64 = row.get(2)?;
+        let created_at: i64 = row.get(3)?;
+        let flags = [
+            (row.get::<_, bool>(4)?, "pending"),
+            (row.get::<_, bool>(5)?, "locked"),
+            (config.verify_email && !row.get::<_, bool>(6)?, "unverified"),
+        ];
+        let flags: Vec<&str> = flags.iter().filter(|f| f.0).map(|f| f.1).collect();
+        Ok(format!(
+            "{}\t{}\t{}\t{}\t{}",
+            username,
+            email.unwrap_or_default(),
+            rendered_size(used),
+            NaiveDateTime::from_timestamp(created_at, 0).format("%Y-%m-%d"),
+            flags.join(",")
+        ))
+    })?;
+    Ok(res.collect::<Result<_, _>>()?)
+}
+
+/// Locking also ends the user's sessions. Their pages stay up
+pub fn set_locked(config: &Config, username: &str, locked: bool) -> Result<(), AdminError> {
+    let conn = open(config)?;
+    let (user_id, _) = find_user(&conn, username)?;
+    conn.execute(
+        "UPDATE user SET locked = (?1) WHERE id = (?2)",
+        params![locked, user_id],
+    )?;
+    if locked {
+        session::delete_all(&conn, &user_id.to_string())?;
+    }
+    Ok(())
+}
+
+/// Renames the user along with their directory under file_directory
+pub fn rename_user(config: &Config, username: &str, new_username: &str) -> Result<(), AdminError> {
+    let username = username.to_lowercase();
+    let new_username = new_username.to_lowercase();
+    check_username(&new_username)?;
+    let mut conn = open(config)?;
+    let (user_id, _) = find_user(&conn, &username)?;
+    if username_taken(&conn, &new_username)? {
+        return Err(AdminError::UserExists(new_username));
+    }
+    let old_dir = Path::new(&config.file_directory).join(&username);
+    let new_dir = Path::new(&config.file_directory).join(&new_username);
+    if new_dir.exists() {
+        return Err(AdminError::Invalid(format!(
+            "{} already exists",
+            new_dir.display()
+        )));
+    }
+    // full paths are file_directory/username/user_path
+    let old_prefix = old_dir.join("").to_string_lossy().to_string();
+    let new_prefix = new_dir.join("").to_string_lossy().to_string();
+    let tx = conn.transaction()?;
+    tx.execute(
+        "UPDATE user SET username = (?1) WHERE id = (?2)",
+        params![new_username, user_id],
+    )?;
+    tx.execute(
+        r#"
+        UPDATE file SET full_path = (?2) || substr(full_path, length(?1) + 1)
+        WHERE user_id = (?3) AND substr(full_path, 1, length(?1)) = (?1)
+        "#,
+        params![old_prefix, new_prefix, user_id],
+    )?;
+    if old_dir.exists() {
+        std::fs::rename(&old_dir, &new_dir)?;
+    }
+    if let Err(e) = tx.commit() {
+        std::fs::rename(&new_dir, &old_dir).ok();
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// None goes back to default_quota_bytes
+pub fn set_quota(config: &Config, username: &str, quota: Option<u64>) -> Result<(), AdminError> {
+    let conn = open(config)?;
+    let (user_id, _) = find_user(&conn, username)?;
+    conn.execute(
+        "UPDATE user SET quota_bytes = (?1) WHERE id = (?2)",
+        params![quota.map(|q| q as i64), user_id],
+    )?;
+    Ok(())
+}
+
+/// Per user, biggest first: files and history against quota, then how much of that is
+/// history. Ends with the totals
+pub fn disk_usage(config: &Config) -> Result<Vec<String>, AdminError> {
+    let conn = open(config)?;
+    let mut stmt = conn.prepare_cached(
+        r#"
+        SELECT user.username, user.quota_bytes,
+            (SELECT COALESCE(SUM(size), 0) FROM file WHERE file.user_id = user.id) AS files,
+            (SELECT COALESCE(SUM(revision.size), 0) FROM revision JOIN file
+                ON revision.file_id = file.id
+                WHERE file.user_id = user.id) AS history
+        FROM user
+        ORDER BY files + history DESC, user.username
+        "#,
+    )?;
+    let rows = stmt.query_map(NO_PARAMS, |row| {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+    })?;
+    let mut lines = vec![];
+    let (mut total_used, mut total_history) = (0, 0);
+    for row in rows {
+        let (username, quota, files, history): (String, Option<i64>, i64, i64) = row?;
+        let quota = quota.unwrap_or(config.default_quota_bytes as i64);
+        let used = files + history;
+        total_used += used;
+        total_history += history;
+        lines.push(format!(
+            "{}\t{} of {}\t{} of it history",
+            username,
+            rendered_size(used),
+            rendered_size(quota),
+            rendered_size(history)
+        ));
+    }
+    lines.push(format!(
+        "total\t{}\t{} of it history",
+        rendered_size(total_used),
+        rendered_size(total_history)
+    ));
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_user_lifecycle() {
+        let dir = std::env::temp_dir().join(format!("flounder-admin-{}", random_hex(4)));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = crate::config::test_config(&dir);
+        crate::db::migrate(&mut Connection::open(&config.db_path).unwrap()).unwrap();
+
+        create_user(&config, "Alice", Some("alice@example.org")).unwrap();
+        assert!(create_user(&config, "alice", None).is_err());
+        assert!(create_user(&config, "no spaces", None).is_err());
+        assert!(dir.join("files/alice/index.gmi").exists());
+
+        rename_user(&config, "alice", "alicia").unwrap();
+        assert!(dir.join("files/alicia/index.gmi").exists());
+        assert!(!dir.join("files/alice").exists());
+        let conn = Connection::open(&config.db_path).unwrap();
+        let full_path: String = conn
+            .query_row("SELECT full_path FROM file", NO_PARAMS, |row| row.get(0))
+            .unwrap();
+        assert!(full_path.ends_with("files/alicia/index.gmi"));
+        // old revisions count against the quota
+        let (user_id, files): (i64, i64) = conn
+            .query_row("SELECT user_id, size FROM file", NO_PARAMS, |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        conn.execute(
+            "INSERT INTO revision (file_id, content, size) SELECT id, 'abc', 3 FROM file",
+            NO_PARAMS,
+        )
+        .unwrap();
+        let (used, _) = crate::db::user_usage(&conn, &user_id.to_string(), &config).unwrap();
+        assert_eq!(used, files + 3);
+        // saving with room for one revision would drop that one, with two it stays
+        assert_eq!(
+            crate::db::pruned_revisions_size(&conn, &full_path, 1).unwrap(),
+            3
+        );
+        assert_eq!(
+            crate::db::pruned_revisions_size(&conn, &full_path, 2).unwrap(),
+            0
+        );
+
+        set_locked(&config, "alicia", true).unwrap();
+        assert!(list_users(&config).unwrap()[0].ends_with("\tlocked"));
+        delete_user(&config, "alicia").unwrap();
+        assert!(list_users(&config).unwrap().is_empty());
+        assert!(!dir.join("files/alicia").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_approve() {
+        let dir = std::env::temp_dir().join(format!("flounder-admin-{}", random_hex(4)));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = crate::config::test_config(&dir);
+        let mut conn = Connection::open(&config.db_path).unwrap();
+        crate::db::migrate(&mut conn).unwrap();
+        conn.execute(
+            "INSERT INTO user (username, pending) VALUES ('bob', 1)",
+            NO_PARAMS,
+        )
+        .unwrap();
+        // nothing public until approved
+        assert!(crate::db::usernames(&conn).unwrap().is_empty());
+        assert!(!crate::db::user_exists(&conn, "bob").unwrap());
+
+        approve(&config, "bob").unwrap();
+        assert_eq!(crate::db::usernames(&conn).unwrap(), vec!["bob"]);
+        assert!(crate::db::full_path(&conn, "bob", "index.gmi")
+            .unwrap()
+            .is_some());
+        assert!(dir.join("files/bob/index.gmi").exists());
+        assert!(approve(&config, "bob").is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,78 +1,468 @@
-use native_tls::TlsConnector;
-use std::io::{Read, Write};
-use std::net::{SocketAddr::V4, SocketAddr::V6, TcpStream, ToSocketAddrs};
-use std::thread;
+use crate::db::Pool;
+use actix_web::error::BlockingError;
+use actix_web::web;
+use chrono::{TimeZone, Utc};
+use openssl::asn1::Asn1Time;
+use openssl::hash::MessageDigest;
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use openssl::x509::X509Ref;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::HashSet;
+use std::fmt;
+use std::net::IpAddr;
 use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpStream};
+use tokio::time::timeout;
 use url::Url;
 
-pub fn get_follow_redirect() {
-    // TODO writeme
+const MAX_REDIRECTS: usize = 5;
+// two digit status, a space, up to 1024 bytes of meta, CRLF
+const MAX_HEADER_LENGTH: usize = 1029;
+const MAX_BODY_SIZE: usize = 4 * 1024 * 1024;
+// per address tried
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// covers the handshake, the request and the whole response
+const READ_TIMEOUT: Duration = Duration::from_secs(15);
+
+pub struct GeminiResponse {
+    pub url: Url, // where the response actually came from, after redirects
+    pub status: u8,
+    pub meta: String,
+    pub body: Vec<u8>,
+}
+
+impl GeminiResponse {
+    pub fn is_success(&self) -> bool {
+        self.status / 10 == 2
+    }
+
+    pub fn is_redirect(&self) -> bool {
+        self.status / 10 == 3
+    }
+
+    /// For 2x responses, meta is the MIME type. Defaults to text/gemini per spec
+    pub fn mime_type(&self) -> Option<&str> {
+        if !self.is_success() {
+            return None;
+        }
+        match self.meta.split(';').next().map(|m| m.trim()) {
+            Some("") | None => Some("text/gemini"),
+            Some(mime) => Some(mime),
+        }
+    }
 }
-pub fn get_gmi_data(url: &str) -> Result<(Option<Vec<u8>>, Vec<u8>), String> {
-    // TODO tls verification
-    println!("{}", url);
-    let url = Url::parse(url).unwrap(); // TODO error handling
-    println!("{:?}", url);
-    let host = url.host_str().unwrap();
+
+#[derive(Debug)]
+pub enum ClientError {
+    InvalidUrl(String),
+    UnsupportedScheme(String),
+    Connect(String),
+    // resolves to loopback, a private network or the like
+    ForbiddenAddress(String),
+    ForbiddenPort(u16),
+    MalformedResponse,
+    TooManyRedirects,
+    RedirectLoop(String),
+    Timeout,
+    TooLarge,
+    Certificate(String),
+    CertificateChanged { host: String, expires_at: i64 },
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::InvalidUrl(url) => write!(f, "Invalid URL: {}", url),
+            ClientError::UnsupportedScheme(scheme) => {
+                write!(f, "Only gemini:// URLs are supported, not {}://", scheme)
+            }
+            ClientError::Connect(e) => write!(f, "{}", e),
+            ClientError::ForbiddenAddress(host) => {
+                write!(f, "{} is not on the public internet", host)
+            }
+            ClientError::ForbiddenPort(port) => {
+                write!(f, "This proxy doesn't connect to port {}", port)
+            }
+            ClientError::MalformedResponse => write!(f, "Server sent a malformed response"),
+            ClientError::TooManyRedirects => {
+                write!(f, "Gave up after {} redirects", MAX_REDIRECTS)
+            }
+            ClientError::RedirectLoop(url) => write!(f, "Redirect loop at {}", url),
+            ClientError::Timeout => write!(f, "Server took too long to respond"),
+            ClientError::TooLarge => {
+                write!(f, "Response is larger than {} bytes", MAX_BODY_SIZE)
+            }
+            ClientError::Certificate(e) => write!(f, "Could not read server certificate: {}", e),
+            ClientError::CertificateChanged { host, expires_at } => write!(
+                f,
+                "{} presented a different certificate than the one first seen, which is valid until {}. \
+                 Someone may be intercepting the connection.",
+                host,
+                Utc.timestamp(*expires_at, 0).format("%Y-%m-%d")
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+/// Follows 3x redirects between gemini:// URLs. A redirect anywhere else is returned as is,
+/// so the caller can decide what to do with it.
+pub async fn get_follow_redirect(
+    url: &str,
+    known_hosts: &Pool,
+    ports: &[u16],
+) -> Result<GeminiResponse, ClientError> {
+    let mut url = Url::parse(url).map_err(|_| ClientError::InvalidUrl(url.to_string()))?;
+    let mut seen = HashSet::new();
+    for _ in 0..=MAX_REDIRECTS {
+        if !seen.insert(url.clone()) {
+            return Err(ClientError::RedirectLoop(url.to_string()));
+        }
+        let response = get_gmi_data(url.as_str(), known_hosts, ports).await?;
+        if !response.is_redirect() {
+            return Ok(response);
+        }
+        // meta may be relative
+        let next = url
+            .join(&response.meta)
+            .map_err(|_| ClientError::InvalidUrl(response.meta.clone()))?;
+        if next.scheme() != "gemini" {
+            return Ok(response);
+        }
+        url = next;
+    }
+    Err(ClientError::TooManyRedirects)
+}
+
+/// A single request, no redirects followed, to one of ports. Certificates are checked against
+/// known_host rather than CAs, since self-signed is the norm in Gemini
+pub async fn get_gmi_data(
+    url: &str,
+    known_hosts: &Pool,
+    ports: &[u16],
+) -> Result<GeminiResponse, ClientError> {
+    let url = Url::parse(url).map_err(|_| ClientError::InvalidUrl(url.to_string()))?;
+    if url.scheme() != "gemini" {
+        return Err(ClientError::UnsupportedScheme(url.scheme().to_string()));
+    }
+    let host = url
+        .host_str()
+        .ok_or_else(|| ClientError::InvalidUrl(url.to_string()))?
+        .to_string();
     let port = url.port().unwrap_or(1965);
+    if !ports.contains(&port) {
+        return Err(ClientError::ForbiddenPort(port));
+    }
     let urlf = format!("{}:{}", host, port);
-
-    let mut builder = TlsConnector::builder();
-    builder.danger_accept_invalid_hostnames(true);
-    builder.danger_accept_invalid_certs(true);
+    let connect_error = |e: &dyn fmt::Display| {
+        ClientError::Connect(format!("Could not connect to {}\n{}", urlf, e))
+    };
 
     //     if let Some(cert) = crate::gemini::certificate::get_certificate(host) {
     //         let der = cert.to_der().unwrap();
     //         let identity = native_tls::Identity::from_pkcs12(&der, "").unwrap();
     //         builder.identity(identity);
     //     };
+    let mut builder = SslConnector::builder(SslMethod::tls()).map_err(|e| connect_error(&e))?;
+    builder.set_verify(SslVerifyMode::NONE);
+    let mut ssl_config = builder.build().configure().map_err(|e| connect_error(&e))?;
+    ssl_config.set_verify_hostname(false);
 
-    let connector = builder.build().unwrap();
-
-    match urlf.to_socket_addrs() {
-        Ok(mut addrs_iter) => match addrs_iter.next() {
-            Some(socket_addr) => {
-                let socket_addr = match socket_addr {
-                    V4(ip) => V4(ip),
-                    V6(ip) => match addrs_iter.next() {
-                        Some(addr) => addr,
-                        None => V6(ip),
-                    },
-                };
-
-                let stream = TcpStream::connect_timeout(&socket_addr, Duration::new(5, 0));
-
-                match stream {
-                    Ok(stream) => {
-                        let mstream = connector.connect(&host, stream);
-
-                        match mstream {
-                            Ok(mut stream) => thread::spawn(move || {
-                                let url = format!("{}\r\n", url);
-                                stream.write_all(url.as_bytes()).unwrap();
-                                let mut res = vec![];
-                                stream.read_to_end(&mut res).unwrap();
-
-                                let clrf_idx = find_clrf(&res);
-                                let content = res.split_off(clrf_idx.unwrap() + 2);
-
-                                Ok((Some(res), content))
-                            })
-                            .join()
-                            .unwrap(),
-                            Err(e) => Err(format!("Could not connect to {}\n{}", urlf, e)),
-                        }
-                    }
-                    Err(e) => Err(format!("Could not connect to {}\n{}", urlf, e)),
-                }
+    // url's host_str keeps the brackets around IPv6 addresses, which lookup_host understands
+    let addrs: Vec<_> = lookup_host(&urlf)
+        .await
+        .map_err(|e| connect_error(&e))?
+        .collect();
+    // every redirect hop comes through here too, so a redirect can't get around this
+    if addrs.iter().any(|addr| !is_public(addr.ip())) {
+        return Err(ClientError::ForbiddenAddress(host));
+    }
+    let mut last_error = connect_error(&"No addresses found");
+    let mut tcp_stream = None;
+    // hosts often publish AAAA records they don't actually answer on, so fall through to the next
+    for addr in addrs {
+        match timeout(CONNECT_TIMEOUT, TcpStream::connect(&addr)).await {
+            Ok(Ok(stream)) => {
+                tcp_stream = Some(stream);
+                break;
             }
-            None => Err(format!("Could not connect to {}", urlf)),
-        },
-        Err(e) => Err(format!("Could not connect to {}\n{}", urlf, e)),
+            Ok(Err(e)) => last_error = connect_error(&e),
+            Err(_) => last_error = connect_error(&"Timed out"),
+        }
     }
+    let tcp_stream = tcp_stream.ok_or(last_error)?;
+
+    let request = async {
+        let sni_host = host.trim_start_matches('[').trim_end_matches(']');
+        let mut stream = tokio_openssl::connect(ssl_config, sni_host, tcp_stream)
+            .await
+            .map_err(|e| connect_error(&e))?;
+        let cert = stream
+            .ssl()
+            .peer_certificate()
+            .ok_or_else(|| ClientError::Certificate("none sent".to_string()))?;
+        // waiting on the pool and SQLite both block, so they run off the async worker. The
+        // connection isn't held across the request, so slow servers don't tie up the pool
+        let (known_hosts, host_port) = (known_hosts.clone(), urlf.clone());
+        web::block(move || {
+            let conn = known_hosts
+                .get()
+                .map_err(|e| ClientError::Certificate(e.to_string()))?;
+            check_known_host(&conn, &host_port, &cert)
+        })
+        .await
+        .map_err(|e| match e {
+            BlockingError::Error(e) => e,
+            BlockingError::Canceled => ClientError::Certificate("check canceled".to_string()),
+        })?;
+        stream
+            .write_all(format!("{}\r\n", url).as_bytes())
+            .await
+            .map_err(|e| ClientError::Connect(e.to_string()))?;
+        read_response(&mut stream).await
+    };
+    let (status, meta, body) = timeout(READ_TIMEOUT, request)
+        .await
+        .map_err(|_| ClientError::Timeout)??;
+    Ok(GeminiResponse {
+        url: url,
+        status: status,
+        meta: meta,
+        body: body,
+    })
+}
+
+/// Keeps the proxy from being pointed at the server itself or its local network
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                // "this network", 0.0.0.0/8
+                || a == 0
+                // carrier-grade NAT, 100.64.0.0/10
+                || (a == 100 && b & 0xc0 == 64)
+                // IETF protocol assignments, 192.0.0.0/24
+                || (a == 192 && b == 0 && c == 0)
+                // benchmarking, 198.18.0.0/15
+                || (a == 198 && b & 0xfe == 18)
+                // reserved, 240.0.0.0/4
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            // ::ffff:a.b.c.d reaches the IPv4 address
+            if segments[..5] == [0; 5] && segments[5] == 0xffff {
+                let [a, b] = segments[6].to_be_bytes();
+                let [c, d] = segments[7].to_be_bytes();
+                return is_public(IpAddr::from([a, b, c, d]));
+            }
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // unique local, fc00::/7
+                || segments[0] & 0xfe00 == 0xfc00
+                // link-local, fe80::/10
+                || segments[0] & 0xffc0 == 0xfe80)
+        }
+    }
+}
+
+/// Reads until the server closes the connection, giving up past MAX_BODY_SIZE
+async fn read_response<R: AsyncRead + Unpin>(
+    reader: R,
+) -> Result<(u8, String, Vec<u8>), ClientError> {
+    let limit = MAX_HEADER_LENGTH + MAX_BODY_SIZE;
+    let mut res = vec![];
+    reader
+        .take(limit as u64 + 1)
+        .read_to_end(&mut res)
+        .await
+        .map_err(|e| ClientError::Connect(e.to_string()))?;
+    if res.len() > limit {
+        return Err(ClientError::TooLarge);
+    }
+    parse_response(res)
+}
+
+/// Trust on first use: the first certificate seen for a host:port is pinned, and a different one
+/// is only accepted once the pinned one has expired
+fn check_known_host(conn: &Connection, host: &str, cert: &X509Ref) -> Result<(), ClientError> {
+    let (fingerprint, expires_at) = fingerprint(cert)?;
+    let db_error = |e: rusqlite::Error| ClientError::Certificate(e.to_string());
+    let pinned: Option<(String, i64)> = conn
+        .query_row(
+            "SELECT fingerprint, expires_at FROM known_host WHERE host = (?1)",
+            &[host],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()
+        .map_err(db_error)?;
+    match pinned {
+        Some((pinned, _)) if pinned == fingerprint => return Ok(()),
+        Some((_, pinned_expires_at)) if pinned_expires_at > Utc::now().timestamp() => {
+            return Err(ClientError::CertificateChanged {
+                host: host.to_string(),
+                expires_at: pinned_expires_at,
+            })
+        }
+        _ => {}
+    }
+    conn.execute(
+        r#"
+        INSERT INTO known_host (host, fingerprint, expires_at) VALUES (?1, ?2, ?3)
+        ON CONFLICT(host) DO UPDATE SET
+        fingerprint = excluded.fingerprint,
+        expires_at = excluded.expires_at,
+        first_seen_at = strftime('%s', 'now')
+        "#,
+        params![host, fingerprint, expires_at],
+    )
+    .map_err(db_error)?;
+    Ok(())
+}
+
+/// SHA-256 of the DER certificate, hex encoded, and its notAfter as a unix timestamp
+fn fingerprint(cert: &X509Ref) -> Result<(String, i64), ClientError> {
+    let cert_error = |e: &dyn fmt::Display| ClientError::Certificate(e.to_string());
+    let digest = cert
+        .digest(MessageDigest::sha256())
+        .map_err(|e| cert_error(&e))?;
+    let fingerprint = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    let since_epoch = Asn1Time::from_unix(0)
+        .and_then(|epoch| epoch.diff(cert.not_after()))
+        .map_err(|e| cert_error(&e))?;
+    let expires_at = since_epoch.days as i64 * 86400 + since_epoch.secs as i64;
+    Ok((fingerprint, expires_at))
+}
+
+/// Splits a raw response into status, meta and body
+fn parse_response(mut res: Vec<u8>) -> Result<(u8, String, Vec<u8>), ClientError> {
+    let clrf_idx = find_clrf(&res).ok_or(ClientError::MalformedResponse)?;
+    if clrf_idx > MAX_HEADER_LENGTH {
+        return Err(ClientError::MalformedResponse);
+    }
+    let body = res.split_off(clrf_idx + 2);
+    res.truncate(clrf_idx);
+    let header = String::from_utf8(res).map_err(|_| ClientError::MalformedResponse)?;
+    let (status, meta) = match header.find(' ') {
+        Some(i) => (&header[..i], header[i + 1..].trim()),
+        None => (header.as_str(), ""),
+    };
+    if status.len() != 2 {
+        return Err(ClientError::MalformedResponse);
+    }
+    let status: u8 = status.parse().map_err(|_| ClientError::MalformedResponse)?;
+    Ok((status, meta.to_string(), body))
 }
 
 fn find_clrf(data: &[u8]) -> Option<usize> {
     let clrf = b"\r\n";
     data.windows(clrf.len()).position(|window| window == clrf)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::x509::{X509Builder, X509};
+
+    fn test_cert(days: u32) -> X509 {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(days).unwrap())
+            .unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        builder.build()
+    }
+
+    #[test]
+    fn test_known_host() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        crate::db::migrate(&mut conn).unwrap();
+        let first = test_cert(30);
+        check_known_host(&conn, "example.org:1965", &first).unwrap();
+        check_known_host(&conn, "example.org:1965", &first).unwrap();
+        let second = test_cert(30);
+        match check_known_host(&conn, "example.org:1965", &second) {
+            Err(ClientError::CertificateChanged { .. }) => {}
+            _ => panic!("changed certificate was accepted"),
+        }
+        // once the pinned one runs out, the new one takes its place
+        conn.execute("UPDATE known_host SET expires_at = 0", rusqlite::NO_PARAMS)
+            .unwrap();
+        check_known_host(&conn, "example.org:1965", &second).unwrap();
+        check_known_host(&conn, "example.org:1965", &second).unwrap();
+    }
+
+    #[test]
+    fn test_is_public() {
+        let public = |ip: &str| is_public(ip.parse().unwrap());
+        assert!(public("93.184.216.34"));
+        assert!(public("100.128.0.1"));
+        assert!(public("198.20.0.1"));
+        assert!(public("2606:2800:220:1:248:1893:25c8:1946"));
+        for ip in &[
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "0.0.0.0",
+            "0.1.2.3",
+            "100.64.0.1",
+            "100.127.255.254",
+            "192.0.0.8",
+            "198.18.0.1",
+            "198.19.255.255",
+            "224.0.0.1",
+            "240.0.0.1",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "ff02::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!public(ip), "{} counted as public", ip);
+        }
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let (status, meta, body) =
+            parse_response(b"20 text/gemini; lang=en\r\n# hi\r\n".to_vec()).unwrap();
+        assert_eq!(status, 20);
+        assert_eq!(meta, "text/gemini; lang=en");
+        assert_eq!(body, b"# hi\r\n");
+
+        assert!(parse_response(b"20 text/gemini".to_vec()).is_err());
+        assert!(parse_response(b"2 text/gemini\r\n".to_vec()).is_err());
+        assert!(parse_response(b"ab text/gemini\r\n".to_vec()).is_err());
+    }
+
+    #[test]
+    fn test_read_response_limit() {
+        let mut res = b"20 text/plain\r\n".to_vec();
+        res.resize(MAX_BODY_SIZE, b'a');
+        assert!(futures::executor::block_on(read_response(res.as_slice())).is_ok());
+        res.resize(MAX_HEADER_LENGTH + MAX_BODY_SIZE + 1, b'a');
+        match futures::executor::block_on(read_response(res.as_slice())) {
+            Err(ClientError::TooLarge) => {}
+            _ => panic!("oversized response was read"),
+        }
+    }
+}
@@ -0,0 +1,373 @@
+use serde::Deserialize;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::Path;
+
+/// Loaded once at startup with Config::load, then shared with the HTTP workers and the Gemini listener
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub db_path: String,
+    pub file_directory: String,
+    #[serde(default)]
+    pub tls_enabled: bool,
+    pub server_name: String,
+    #[serde(default)]
+    pub serve_all_content: bool, // Don't use nginx for anything. In production probably we wanna use nginx for static files
+    #[serde(default = "default_static_path")]
+    pub static_path: String,
+    // Where gemini:// links on HTTP pages go. Defaults to Flounder's own /proxy/
+    pub proxy_url: Option<String>,
+    // Seconds a proxied page is served from cache. 0 turns caching off
+    #[serde(default = "default_proxy_cache_ttl")]
+    pub proxy_cache_ttl: u64,
+    #[serde(default = "default_proxy_cache_max_entries")]
+    pub proxy_cache_max_entries: u32,
+    #[serde(default = "default_proxy_cache_max_bytes")]
+    pub proxy_cache_max_bytes: u64,
+    // Ports the proxy connects to. Anything else on a public host is likely some other service
+    #[serde(default = "default_proxy_ports")]
+    pub proxy_ports: Vec<u16>,
+    // Gemini requires TLS. Self-signed is normal here; clients pin on first use
+    pub gemini_cert_path: String,
+    pub gemini_key_path: String,
+    // Only needed when tls_enabled. Should be a wildcard cert covering *.server_name
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+    // Every address gets a listener, e.g. ["0.0.0.0:443", "[::]:443"]
+    #[serde(default = "default_http_bind")]
+    pub http_bind: Vec<String>,
+    #[serde(default = "default_gemini_bind")]
+    pub gemini_bind: String,
+    pub unix_socket: Option<String>,
+    pub workers: Option<usize>, // defaults to one per core
+    // Database connections, shared by the HTTP workers and the Gemini server
+    #[serde(default = "default_db_pool_size")]
+    pub db_pool_size: u32,
+    #[serde(default = "default_max_files_per_user")]
+    pub max_files_per_user: u32,
+    #[serde(default)]
+    pub registration: Registration,
+    // Unused invite codes a user can have at once
+    #[serde(default = "default_invites_per_user")]
+    pub invites_per_user: u32,
+    // Per-user overrides live in user.quota_bytes
+    #[serde(default = "default_quota_bytes")]
+    pub default_quota_bytes: u64,
+    // Hex, at least 32 bytes. Without it, a key is generated into session_key_path on first run
+    pub session_key: Option<String>,
+    #[serde(default = "default_session_key_path")]
+    pub session_key_path: String,
+    #[serde(default = "default_session_lifetime_days")]
+    pub session_lifetime_days: u32,
+    // Password reset and verification mail. With neither mail_dir nor smtp_relay, only admins can issue reset links
+    pub mail_from: Option<String>,  // defaults to flounder@server_name
+    pub mail_dir: Option<String>,   // write .eml files here instead of sending
+    pub smtp_relay: Option<String>, // e.g. "127.0.0.1:25"
+    // Email new users a link to confirm their address. Needs mail_dir or smtp_relay
+    #[serde(default)]
+    pub verify_email: bool,
+    // With verify_email, whether users can write files before following the link
+    #[serde(default = "default_unverified_can_publish")]
+    pub unverified_can_publish: bool,
+    // Old versions kept per file. 0 turns history off
+    #[serde(default = "default_max_revisions")]
+    pub max_revisions: u32,
+}
+
+/// Who can sign up
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Registration {
+    Open,
+    // Only with an unused invite code
+    Invite,
+    // New accounts wait for `flounder admin approve`, unless they came with an invite code
+    Approval,
+}
+
+impl Default for Registration {
+    fn default() -> Registration {
+        Registration::Open
+    }
+}
+
+fn default_static_path() -> String {
+    "static".to_string()
+}
+
+fn default_proxy_cache_ttl() -> u64 {
+    300
+}
+
+fn default_proxy_cache_max_entries() -> u32 {
+    256
+}
+
+fn default_proxy_cache_max_bytes() -> u64 {
+    32 * 1024 * 1024
+}
+
+fn default_proxy_ports() -> Vec<u16> {
+    vec![1965]
+}
+
+fn default_http_bind() -> Vec<String> {
+    vec!["127.0.0.1:8088".to_string()]
+}
+
+fn default_gemini_bind() -> String {
+    "0.0.0.0:1965".to_string()
+}
+
+fn default_db_pool_size() -> u32 {
+    8
+}
+
+fn default_max_files_per_user() -> u32 {
+    128
+}
+
+fn default_invites_per_user() -> u32 {
+    5
+}
+
+fn default_quota_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_session_key_path() -> String {
+    "session.key".to_string()
+}
+
+fn default_session_lifetime_days() -> u32 {
+    30
+}
+
+fn default_unverified_can_publish() -> bool {
+    true
+}
+
+fn default_max_revisions() -> u32 {
+    10
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(String, std::io::Error),
+    Parse(toml::de::Error),
+    Invalid(&'static str, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "Could not read config file {}: {}", path, e),
+            ConfigError::Parse(e) => write!(f, "Could not parse config: {}", e),
+            ConfigError::Invalid(field, message) => write!(f, "Invalid `{}`: {}", field, message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    pub fn load(path: &str) -> Result<Config, ConfigError> {
+        let config_str =
+            std::fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_string(), e))?;
+        let config = Config::parse(&config_str)?;
+        config.check_files()?;
+        Ok(config)
+    }
+
+    /// Checks the values but not the files they point at, which is load's job
+    pub fn parse(config_str: &str) -> Result<Config, ConfigError> {
+        let config: Config = toml::from_str(config_str).map_err(ConfigError::Parse)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.server_host().is_empty() {
+            return Err(ConfigError::Invalid(
+                "server_name",
+                "must not be empty".into(),
+            ));
+        }
+        if self.http_bind.is_empty() && self.unix_socket.is_none() {
+            return Err(ConfigError::Invalid(
+                "http_bind",
+                "need at least one address unless unix_socket is set".into(),
+            ));
+        }
+        for addr in &self.http_bind {
+            if addr.parse::<SocketAddr>().is_err() {
+                return Err(ConfigError::Invalid(
+                    "http_bind",
+                    format!("{} is not an address like 127.0.0.1:8088", addr),
+                ));
+            }
+        }
+        if self.gemini_bind.parse::<SocketAddr>().is_err() {
+            return Err(ConfigError::Invalid(
+                "gemini_bind",
+                format!("{} is not an address like 0.0.0.0:1965", self.gemini_bind),
+            ));
+        }
+        if self.mail_dir.is_some() && self.smtp_relay.is_some() {
+            return Err(ConfigError::Invalid(
+                "smtp_relay",
+                "set either mail_dir or smtp_relay, not both".into(),
+            ));
+        }
+        if self.verify_email && self.mail_dir.is_none() && self.smtp_relay.is_none() {
+            return Err(ConfigError::Invalid(
+                "verify_email",
+                "needs mail_dir or smtp_relay to send the links".into(),
+            ));
+        }
+        if self.workers == Some(0) {
+            return Err(ConfigError::Invalid("workers", "must be at least 1".into()));
+        }
+        if self.db_pool_size == 0 {
+            return Err(ConfigError::Invalid(
+                "db_pool_size",
+                "must be at least 1".into(),
+            ));
+        }
+        if let Some(key) = &self.session_key {
+            if crate::session::decode_hex(key).map_or(true, |key| key.len() < 32) {
+                return Err(ConfigError::Invalid(
+                    "session_key",
+                    "must be at least 64 hex characters".into(),
+                ));
+            }
+        }
+        if self.tls_enabled {
+            if self.tls_cert_path.is_none() {
+                return Err(ConfigError::Invalid(
+                    "tls_cert_path",
+                    "required when tls_enabled is true".into(),
+                ));
+            }
+            if self.tls_key_path.is_none() {
+                return Err(ConfigError::Invalid(
+                    "tls_key_path",
+                    "required when tls_enabled is true".into(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_files(&self) -> Result<(), ConfigError> {
+        let files = [
+            ("gemini_cert_path", Some(&self.gemini_cert_path)),
+            ("gemini_key_path", Some(&self.gemini_key_path)),
+            ("tls_cert_path", self.tls_cert_path.as_ref()),
+            ("tls_key_path", self.tls_key_path.as_ref()),
+        ];
+        for (field, path) in files.iter() {
+            if let Some(path) = path {
+                if !Path::new(path).is_file() {
+                    return Err(ConfigError::Invalid(
+                        field,
+                        format!("{} does not exist", path),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// server_name without the port
+    pub fn server_host(&self) -> String {
+        self.server_name
+            .split(':')
+            .next()
+            .unwrap_or("")
+            .to_lowercase()
+    }
+
+    pub fn session_lifetime_secs(&self) -> u64 {
+        self.session_lifetime_days as u64 * 24 * 60 * 60
+    }
+
+    /// Where this server is reachable over HTTP, for links that leave the site, like in emails
+    pub fn base_url(&self) -> String {
+        let scheme = if self.tls_enabled { "https" } else { "http" };
+        format!("{}://{}", scheme, self.server_name)
+    }
+
+    /// A user's capsule over HTTP, <username>.<server_name>, with a trailing slash
+    pub fn capsule_url(&self, username: &str) -> String {
+        self.base_url()
+            .replacen("://", &format!("://{}.", username), 1)
+            + "/"
+    }
+
+    pub fn proxy_url(&self) -> String {
+        match &self.proxy_url {
+            Some(url) => url.clone(),
+            None => format!("{}/proxy/", self.base_url()),
+        }
+    }
+
+    pub fn mail_from(&self) -> String {
+        match &self.mail_from {
+            Some(from) => from.clone(),
+            None => format!("flounder@{}", self.server_host()),
+        }
+    }
+}
+
+/// The smallest valid config, with the database and files under dir, for tests to adjust
+#[cfg(test)]
+pub fn test_config(dir: &Path) -> Config {
+    Config::parse(&format!(
+        r#"
+        db_path = "{0}/flounder.db"
+        file_directory = "{0}/files"
+        server_name = "flounder.local"
+        gemini_cert_path = "{0}/cert.pem"
+        gemini_key_path = "{0}/key.pem"
+        "#,
+        dir.display()
+    ))
+    .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_defaults() {
+        let mut config = test_config(Path::new("tmp"));
+        config.server_name = "flounder.local:5000".to_string();
+        assert!(!config.tls_enabled);
+        assert_eq!(config.static_path, "static");
+        assert_eq!(config.http_bind, vec!["127.0.0.1:8088"]);
+        assert_eq!(config.server_host(), "flounder.local");
+        assert_eq!(config.proxy_url(), "http://flounder.local:5000/proxy/");
+        assert_eq!(
+            config.capsule_url("alice"),
+            "http://alice.flounder.local:5000/"
+        );
+        assert_eq!(config.registration, Registration::Open);
+    }
+
+    #[test]
+    fn test_errors_name_field() {
+        let mut config = test_config(Path::new("tmp"));
+        config.http_bind = vec!["localhost".to_string()];
+        let err = config.validate().unwrap_err();
+        assert!(err.to_string().contains("http_bind"));
+        // test_config's cert and key don't exist
+        let err = config.check_files().unwrap_err();
+        assert!(err.to_string().contains("gemini_cert_path"));
+
+        let err = Config::parse(r#"db_path = "flounder.db""#).unwrap_err();
+        assert!(err.to_string().contains("file_directory"));
+    }
+}
@@ -0,0 +1,60 @@
+/// Cross-site request forgery protection
+use crate::session::random_hex;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::{HttpMessage, HttpRequest, HttpResponse};
+
+static COOKIE_NAME: &str = "csrf";
+
+/// Constant time, so the token can't be guessed a byte at a time
+pub fn tokens_match(expected: &str, given: &str) -> bool {
+    !expected.is_empty()
+        && expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Forms behind a login carry their session's token (session.csrf_token). Login and register
+/// happen before there is a session, so those compare the form field against this random
+/// cookie instead: another site can post the form, but it can't read or set our cookie.
+///
+/// The browser's pre-login token, or a fresh one. Pass the response through with_cookie
+pub fn anonymous_token(r: &HttpRequest) -> String {
+    match r.cookie(COOKIE_NAME) {
+        Some(cookie) if !cookie.value().is_empty() => cookie.value().to_string(),
+        _ => random_hex(32),
+    }
+}
+
+pub fn check_anonymous(r: &HttpRequest, token: &str) -> bool {
+    match r.cookie(COOKIE_NAME) {
+        Some(cookie) => tokens_match(cookie.value(), token),
+        None => false,
+    }
+}
+
+pub fn with_cookie(mut response: HttpResponse, token: &str, secure: bool) -> HttpResponse {
+    let cookie = Cookie::build(COOKIE_NAME, token.to_string())
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .secure(secure)
+        .finish();
+    response.add_cookie(&cookie).ok();
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens_match() {
+        assert!(tokens_match("abc123", "abc123"));
+        assert!(!tokens_match("abc123", "abc124"));
+        assert!(!tokens_match("abc123", "abc12"));
+        assert!(!tokens_match("", ""));
+    }
+}
@@ -1 +1,598 @@
-// TODO pre-prepare statements
+/// The connection pool, schema migrations, and the queries behind the HTTP and Gemini handlers
+use crate::Config;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{params, Connection, ErrorCode, OptionalExtension, NO_PARAMS};
+use std::fmt;
+use std::time::Duration;
+
+pub type Pool = r2d2::Pool<SqliteConnectionManager>;
+
+// How long a connection waits on another's write lock before giving up with SQLITE_BUSY
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+// How long a request waits for a free connection
+const POOL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Shared by the HTTP workers and the Gemini server. In WAL mode readers don't wait on a writer,
+/// so the index page keeps loading while someone uploads
+pub fn pool(config: &Config) -> Result<Pool, r2d2::Error> {
+    let manager = SqliteConnectionManager::file(&config.db_path).with_init(|conn| configure(conn));
+    r2d2::Pool::builder()
+        .max_size(config.db_pool_size)
+        .connection_timeout(POOL_TIMEOUT)
+        .build(manager)
+}
+
+/// A single connection set up like the pool's, for the CLI. It can run alongside the server
+pub fn open(config: &Config) -> rusqlite::Result<Connection> {
+    let conn = Connection::open(&config.db_path)?;
+    configure(&conn)?;
+    Ok(conn)
+}
+
+fn configure(conn: &Connection) -> rusqlite::Result<()> {
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    // NORMAL is safe with WAL: a power cut can lose the last commits, not corrupt anything
+    conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL;")
+}
+
+struct Migration {
+    sql: &'static str,
+    // for what SQL can't do by itself, run after sql in the same transaction
+    then: Option<fn(&Connection) -> rusqlite::Result<()>>,
+}
+
+// Migration 1 is the original schema.sql. Never edit one that has shipped: add the next file
+// in migrations/ and list it here
+static MIGRATIONS: &[Migration] = &[
+    Migration {
+        sql: include_str!("../migrations/001_initial.sql"),
+        then: None,
+    },
+    Migration {
+        sql: include_str!("../migrations/002_file_sizes_and_quotas.sql"),
+        then: Some(backfill_file_sizes),
+    },
+    Migration {
+        sql: include_str!("../migrations/003_revisions.sql"),
+        then: None,
+    },
+    Migration {
+        sql: include_str!("../migrations/004_known_hosts.sql"),
+        then: None,
+    },
+    Migration {
+        sql: include_str!("../migrations/005_proxy_cache.sql"),
+        then: None,
+    },
+    Migration {
+        sql: include_str!("../migrations/006_sessions.sql"),
+        then: None,
+    },
+    Migration {
+        sql: include_str!("../migrations/007_password_resets.sql"),
+        then: None,
+    },
+    Migration {
+        sql: include_str!("../migrations/008_email_verification.sql"),
+        then: None,
+    },
+    Migration {
+        sql: include_str!("../migrations/009_invites.sql"),
+        then: None,
+    },
+    Migration {
+        sql: include_str!("../migrations/010_locked_users.sql"),
+        then: None,
+    },
+];
+
+#[derive(Debug)]
+pub enum MigrationError {
+    Db(rusqlite::Error),
+    // the database has been migrated by a newer Flounder
+    TooNew(u32),
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MigrationError::Db(e) => write!(f, "Could not migrate the database: {}", e),
+            MigrationError::TooNew(version) => write!(
+                f,
+                "The database is at schema version {}, but this Flounder only knows up to {}",
+                version,
+                latest_version()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {}
+
+impl From<rusqlite::Error> for MigrationError {
+    fn from(e: rusqlite::Error) -> MigrationError {
+        MigrationError::Db(e)
+    }
+}
+
+pub fn latest_version() -> u32 {
+    MIGRATIONS.len() as u32
+}
+
+pub fn schema_version(conn: &Connection) -> rusqlite::Result<u32> {
+    conn.pragma_query_value(None, "user_version", |row| row.get(0))
+}
+
+/// Brings the database up to date. Returns how many migrations ran. PRAGMA user_version counts
+/// how many have run; each pending one runs in its own transaction, in order
+pub fn migrate(conn: &mut Connection) -> Result<u32, MigrationError> {
+    let mut version = schema_version(conn)?;
+    // databases set up by hand from schema.sql predate the version number
+    if version == 0 && table_exists(conn, "user")? {
+        version = 1;
+        conn.pragma_update(None, "user_version", &version)?;
+    }
+    if version > latest_version() {
+        return Err(MigrationError::TooNew(version));
+    }
+    let start = version;
+    for migration in &MIGRATIONS[version as usize..] {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration.sql)?;
+        if let Some(then) = migration.then {
+            then(&tx)?;
+        }
+        version += 1;
+        tx.pragma_update(None, "user_version", &version)?;
+        tx.commit()?;
+        log::info!("Migrated the database to schema version {}", version);
+    }
+    Ok(version - start)
+}
+
+fn table_exists(conn: &Connection, name: &str) -> rusqlite::Result<bool> {
+    conn.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = (?1)",
+        &[name],
+        |row| row.get(0),
+    )
+}
+
+/// Files from before sizes were tracked count as empty until measured
+fn backfill_file_sizes(conn: &Connection) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare("SELECT id, full_path FROM file")?;
+    let rows = stmt.query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))?;
+    for row in rows {
+        let (id, full_path): (i64, String) = row?;
+        let size = std::fs::metadata(&full_path).map_or(0, |m| m.len());
+        conn.execute(
+            "UPDATE file SET size = (?1) WHERE id = (?2)",
+            params![size as i64, id],
+        )?;
+    }
+    Ok(())
+}
+
+pub struct LoginUser {
+    pub id: i64,
+    pub password_hash: Option<String>,
+    pub pending: bool,
+    pub locked: bool,
+}
+
+pub fn login_user(conn: &Connection, username: &str) -> rusqlite::Result<Option<LoginUser>> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+        SELECT id, password_hash, pending, locked FROM user
+        WHERE user.username = (?)
+        "#,
+    )?;
+    stmt.query_row(&[username], |row| {
+        Ok(LoginUser {
+            id: row.get(0)?,
+            password_hash: row.get(1)?,
+            pending: row.get(2)?,
+            locked: row.get(3)?,
+        })
+    })
+    .optional()
+}
+
+pub fn password_hash(conn: &Connection, user_id: &str) -> rusqlite::Result<Option<String>> {
+    let mut stmt = conn.prepare_cached("SELECT password_hash FROM user WHERE id = (?1)")?;
+    Ok(stmt
+        .query_row(&[user_id], |row| row.get(0))
+        .optional()?
+        .flatten())
+}
+
+/// (id, username, email) of the account with that username or email address
+pub fn find_account(
+    conn: &Connection,
+    username_or_email: &str,
+) -> rusqlite::Result<Option<(i64, String, Option<String>)>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT id, username, email FROM user WHERE username = lower(?1) OR email = (?1)",
+    )?;
+    stmt.query_row(&[username_or_email], |row| {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+    })
+    .optional()
+}
+
+/// The new user's id, or None if the username or email is taken
+pub fn insert_user(
+    conn: &Connection,
+    username: &str,
+    email: &str,
+    password_hash: &str,
+    pending: bool,
+) -> rusqlite::Result<Option<i64>> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+        INSERT INTO user (username, email, password_hash, pending)
+        VALUES (?1, ?2, ?3, ?4)
+        "#,
+    )?;
+    match stmt.execute(params![username, email, password_hash, pending]) {
+        Ok(_) => Ok(Some(conn.last_insert_rowid())),
+        Err(rusqlite::Error::SqliteFailure(e, _)) if e.code == ErrorCode::ConstraintViolation => {
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether the user has a public site. Accounts waiting for approval don't
+pub fn user_exists(conn: &Connection, username: &str) -> rusqlite::Result<bool> {
+    let mut stmt =
+        conn.prepare_cached("SELECT 1 FROM user WHERE username = (?1) AND pending = 0")?;
+    Ok(stmt
+        .query_row(&[username], |_| Ok(()))
+        .optional()?
+        .is_some())
+}
+
+pub fn usernames(conn: &Connection) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+        SELECT user.username from user
+        WHERE user.pending = 0
+        ORDER BY user.username
+        "#,
+    )?;
+    let res = stmt.query_map(NO_PARAMS, |row| row.get(0))?;
+    res.collect()
+}
+
+pub struct FileUpdate {
+    pub username: String,
+    pub user_path: String,
+    pub updated_at: u32,
+}
+
+/// The most recently updated files, for the home page
+pub fn recent_files(conn: &Connection, limit: u32) -> rusqlite::Result<Vec<FileUpdate>> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+        SELECT user.username, file.user_path, file.updated_at
+        FROM file
+        JOIN user
+        ON file.user_id = user.id
+        WHERE user.pending = 0
+        ORDER BY file.updated_at DESC
+        LIMIT (?)
+        "#,
+    )?;
+    let res = stmt.query_map(&[limit], |row| {
+        Ok(FileUpdate {
+            username: row.get(0)?,
+            user_path: row.get(1)?,
+            updated_at: row.get(2)?,
+        })
+    })?;
+    res.collect()
+}
+
+/// The user's file paths, sorted
+pub fn user_paths(conn: &Connection, user_id: &str) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+        SELECT file.user_path
+        FROM file where user_id = (?)
+        ORDER BY user_path
+        "#,
+    )?;
+    let res = stmt.query_map(&[user_id], |row| row.get(0))?;
+    res.collect()
+}
+
+/// Where a file is on disk, looked up by the owner's name. Nothing of a pending account's is
+/// served
+pub fn full_path(
+    conn: &Connection,
+    username: &str,
+    user_path: &str,
+) -> rusqlite::Result<Option<String>> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+        SELECT file.full_path FROM file
+        JOIN user
+        ON file.user_id = user.id
+        WHERE user.username = (?1) AND file.user_path = (?2) AND user.pending = 0
+        "#,
+    )?;
+    stmt.query_row(&[username, user_path], |row| row.get(0))
+        .optional()
+}
+
+/// Bytes used, counting old revisions, and bytes allowed. user.quota_bytes overrides the
+/// server default
+pub fn user_usage(
+    conn: &Connection,
+    user_id: &str,
+    config: &Config,
+) -> rusqlite::Result<(i64, i64)> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+        SELECT
+            (SELECT COALESCE(SUM(size), 0) FROM file WHERE file.user_id = user.id)
+            + (SELECT COALESCE(SUM(revision.size), 0) FROM revision JOIN file
+                ON revision.file_id = file.id
+                WHERE file.user_id = user.id),
+            user.quota_bytes
+        FROM user
+        WHERE user.id = (?)
+        "#,
+    )?;
+    let (used, quota): (i64, Option<i64>) =
+        stmt.query_row(&[user_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    Ok((used, quota.unwrap_or(config.default_quota_bytes as i64)))
+}
+
+/// How many files the user has other than the one at full_path, which is about to be
+/// overwritten
+pub fn other_files_count(
+    conn: &Connection,
+    user_id: &str,
+    full_path: &str,
+) -> rusqlite::Result<u32> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+        SELECT COUNT(*) FROM file
+        where user_id = (?1) AND full_path != (?2)
+        "#,
+    )?;
+    stmt.query_row(&[user_id, full_path], |r| r.get(0))
+}
+
+/// Size of the file at full_path as last saved, if there is one
+pub fn file_size(conn: &Connection, full_path: &str) -> rusqlite::Result<Option<i64>> {
+    let mut stmt = conn.prepare_cached("SELECT size FROM file WHERE full_path = (?)")?;
+    stmt.query_row(&[full_path], |r| r.get(0)).optional()
+}
+
+/// Records a file that was just written. Rewriting one bumps its updated_at
+pub fn upsert_file(
+    conn: &Connection,
+    user_path: &str,
+    user_id: &str,
+    full_path: &str,
+    size: usize,
+) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+        INSERT INTO file (user_path, user_id, full_path, size)
+        VALUES (?1, ?2, ?3, ?4)
+        ON CONFLICT(full_path) DO UPDATE SET
+        updated_at=strftime('%s', 'now'),
+        size=excluded.size
+        "#,
+    )?;
+    stmt.execute(params![user_path, user_id, full_path, size as i64])?;
+    Ok(())
+}
+
+/// The file's row and its revisions. The file itself is the caller's to remove
+pub fn delete_file(conn: &Connection, full_path: &str) -> rusqlite::Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    tx.execute(
+        r#"
+        DELETE FROM revision where file_id IN (
+            SELECT id FROM file where file.full_path = (?)
+        )
+        "#,
+        &[full_path],
+    )?;
+    tx.execute("DELETE FROM file where file.full_path = (?)", &[full_path])?;
+    tx.commit()
+}
+
+/// Keep what's on disk as a revision before it gets overwritten, and prune old ones
+pub fn save_revision(
+    conn: &Connection,
+    full_path: &str,
+    max_revisions: u32,
+) -> rusqlite::Result<()> {
+    if max_revisions == 0 {
+        return Ok(());
+    }
+    let mut stmt = conn.prepare_cached(
+        r#"
+        SELECT id, updated_at FROM file
+        WHERE full_path = (?)
+        "#,
+    )?;
+    let (file_id, updated_at): (i64, i64) = match stmt
+        .query_row(&[full_path], |r| Ok((r.get(0)?, r.get(1)?)))
+        .optional()?
+    {
+        Some(row) => row,
+        None => return Ok(()),
+    };
+    let content = match std::fs::read(full_path) {
+        Ok(content) => content,
+        Err(_) => return Ok(()),
+    };
+    let mut stmt = conn.prepare_cached(
+        r#"
+        INSERT INTO revision (file_id, content, size, created_at)
+        VALUES (?1, ?2, ?3, ?4)
+        "#,
+    )?;
+    stmt.execute(params![file_id, content, content.len() as i64, updated_at])?;
+    let mut stmt = conn.prepare_cached(
+        r#"
+        DELETE FROM revision
+        WHERE file_id = (?1) AND id NOT IN (
+            SELECT id FROM revision WHERE file_id = (?1)
+            ORDER BY id DESC LIMIT (?2)
+        )
+        "#,
+    )?;
+    stmt.execute(params![file_id, max_revisions])?;
+    Ok(())
+}
+
+/// Bytes of the file's revisions that saving one more would prune
+pub fn pruned_revisions_size(
+    conn: &Connection,
+    full_path: &str,
+    max_revisions: u32,
+) -> rusqlite::Result<i64> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+        SELECT COALESCE(SUM(revision.size), 0) FROM revision
+        JOIN file
+        ON revision.file_id = file.id
+        WHERE file.full_path = (?1) AND revision.id NOT IN (
+            SELECT revision.id FROM revision
+            JOIN file
+            ON revision.file_id = file.id
+            WHERE file.full_path = (?1)
+            ORDER BY revision.id DESC LIMIT (?2)
+        )
+        "#,
+    )?;
+    // the new revision takes one of the max_revisions places
+    stmt.query_row(params![full_path, max_revisions - 1], |r| r.get(0))
+}
+
+pub struct Revision {
+    pub id: u32,
+    pub size: i64,
+    pub created_at: u32,
+}
+
+/// Revisions of one of the user's files, newest first
+pub fn revisions(
+    conn: &Connection,
+    user_id: &str,
+    user_path: &str,
+) -> rusqlite::Result<Vec<Revision>> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+        SELECT revision.id, revision.size, revision.created_at
+        FROM revision
+        JOIN file
+        ON revision.file_id = file.id
+        WHERE file.user_id = (?1) AND file.user_path = (?2)
+        ORDER BY revision.id DESC
+        "#,
+    )?;
+    let res = stmt.query_map(&[user_id, user_path], |row| {
+        Ok(Revision {
+            id: row.get(0)?,
+            size: row.get(1)?,
+            created_at: row.get(2)?,
+        })
+    })?;
+    res.collect()
+}
+
+/// Content of a revision of one of the user's files. None for the current version
+pub fn revision_content(
+    conn: &Connection,
+    user_id: &str,
+    user_path: &str,
+    revision_id: Option<u32>,
+) -> rusqlite::Result<Option<Vec<u8>>> {
+    if let Some(revision_id) = revision_id {
+        let mut stmt = conn.prepare_cached(
+            r#"
+            SELECT revision.content FROM revision
+            JOIN file
+            ON revision.file_id = file.id
+            WHERE file.user_id = (?1) AND file.user_path = (?2) AND revision.id = (?3)
+            "#,
+        )?;
+        return stmt
+            .query_row(params![user_id, user_path, revision_id], |r| r.get(0))
+            .optional();
+    }
+    let mut stmt = conn.prepare_cached(
+        r#"
+        SELECT full_path FROM file
+        WHERE user_id = (?1) AND user_path = (?2)
+        "#,
+    )?;
+    let full_path: Option<String> = stmt
+        .query_row(&[user_id, user_path], |r| r.get(0))
+        .optional()?;
+    Ok(full_path.and_then(|full_path| std::fs::read(full_path).ok()))
+}
+
+/// (full_path, username) of every twtxt.txt
+pub fn twtxt_files(conn: &Connection) -> rusqlite::Result<Vec<(String, String)>> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+        SELECT full_path, username FROM file
+        JOIN user
+        ON file.user_id = user.id
+        WHERE user_path = 'twtxt.txt' AND user.pending = 0
+        "#,
+    )?;
+    let res = stmt.query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))?;
+    res.collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrate() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        assert_eq!(migrate(&mut conn).unwrap(), latest_version());
+        assert_eq!(schema_version(&conn).unwrap(), latest_version());
+        assert_eq!(migrate(&mut conn).unwrap(), 0);
+
+        // set up by hand from the original schema.sql, with a user and a file that's on disk
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(MIGRATIONS[0].sql).unwrap();
+        conn.execute("INSERT INTO user (username) VALUES ('alice')", NO_PARAMS)
+            .unwrap();
+        conn.execute(
+            "INSERT INTO file (user_path, full_path) VALUES ('c', 'Cargo.toml')",
+            NO_PARAMS,
+        )
+        .unwrap();
+        assert_eq!(migrate(&mut conn).unwrap(), latest_version() - 1);
+        let size: i64 = conn
+            .query_row("SELECT size FROM file", NO_PARAMS, |row| row.get(0))
+            .unwrap();
+        assert_eq!(size as u64, std::fs::metadata("Cargo.toml").unwrap().len());
+        let verified: bool = conn
+            .query_row(
+                "SELECT email_verified_at IS NOT NULL FROM user",
+                NO_PARAMS,
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert!(verified);
+
+        conn.pragma_update(None, "user_version", &(latest_version() + 1))
+            .unwrap();
+        assert!(migrate(&mut conn).is_err());
+    }
+}
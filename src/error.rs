@@ -1,42 +1,188 @@
-use actix_web::{Error, HttpResponse, ResponseError};
-use derive_more::Display; // naming it clearly for illustration purposes
+/// Errors from request handlers, HTTP and Gemini both
+use crate::templates::ErrorTemplate;
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use askama::Template;
+use std::error::Error;
+use std::fmt;
 
-#[derive(Debug, Display)]
+#[derive(Debug)]
 pub enum FlounderError {
-    MiscError, // should not occur.
-    UnauthorizedError,
+    NotFound,
+    // not logged in, an expired session, or a CSRF token that doesn't match
+    Unauthorized,
+    // problems with what the user sent, as messages to show them
+    Validation(Vec<String>),
+    QuotaExceeded { quota: i64 },
+    Db(rusqlite::Error),
+    // no free connection in time
+    Pool(r2d2::Error),
+    Io(std::io::Error),
+    Template(askama::Error),
+    Multipart(actix_multipart::MultipartError),
+    // a bug: something the code relies on didn't hold
+    Internal(&'static str),
+}
+
+impl FlounderError {
+    pub fn validation(message: &str) -> FlounderError {
+        FlounderError::Validation(vec![message.to_string()])
+    }
+
+    /// Messages for the form the user came from, for the errors they can do something about
+    pub fn form_errors(&self) -> Option<Vec<String>> {
+        match self {
+            FlounderError::Validation(messages) => Some(messages.clone()),
+            FlounderError::QuotaExceeded { .. } => Some(vec![self.to_string()]),
+            _ => None,
+        }
+    }
+
+    /// https://gemini.circumlunar.space/docs/specification.html, appendix 1
+    pub fn gemini_status(&self) -> u8 {
+        match self {
+            FlounderError::NotFound => 51,
+            // 6x is for client certificates, which Flounder never asks for
+            FlounderError::Validation(_)
+            | FlounderError::Multipart(_)
+            | FlounderError::Unauthorized => 59,
+            FlounderError::QuotaExceeded { .. } => 50,
+            FlounderError::Pool(_) => 41,
+            _ => 40,
+        }
+    }
+
+    /// Server-side failures get logged with their cause; the user's own mistakes don't
+    pub fn log(&self) {
+        match self {
+            FlounderError::Internal(what) => log::error!("Internal error: {}", what),
+            FlounderError::Multipart(e) => log::info!("{}: {}", self, e),
+            _ => {
+                if let Some(source) = self.source() {
+                    log::error!("{}: {}", self, source);
+                }
+            }
+        }
+    }
+}
+
+// What the user sees. The source error, where there is one, only goes to the log
+impl fmt::Display for FlounderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FlounderError::NotFound => write!(f, "Not found"),
+            FlounderError::Unauthorized => write!(
+                f,
+                "You're not logged in, or your session expired. Log in and try again."
+            ),
+            FlounderError::Validation(messages) => write!(f, "{}", messages.join(" ")),
+            FlounderError::QuotaExceeded { quota } => write!(
+                f,
+                "This would put you over your {} quota. Delete some files to make room.",
+                crate::utils::rendered_size(*quota)
+            ),
+            FlounderError::Db(_) => write!(f, "Database error"),
+            FlounderError::Pool(_) => write!(f, "The server is busy. Try again in a moment."),
+            FlounderError::Io(_) => write!(f, "Could not read or write a file"),
+            FlounderError::Template(_) => write!(f, "Could not render the page"),
+            FlounderError::Multipart(_) => write!(f, "Malformed upload"),
+            FlounderError::Internal(_) => write!(f, "Something went wrong"),
+        }
+    }
+}
+
+impl Error for FlounderError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FlounderError::Db(e) => Some(e),
+            FlounderError::Pool(e) => Some(e),
+            FlounderError::Io(e) => Some(e),
+            FlounderError::Template(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// error.html with the given status. Plain text if even that won't render
+pub fn error_page(status: StatusCode, message: &str) -> HttpResponse {
+    let template = ErrorTemplate {
+        error: message.to_string(),
+    };
+    match template.render() {
+        Ok(body) => HttpResponse::build(status)
+            .content_type("text/html")
+            .body(body),
+        Err(_) => HttpResponse::build(status)
+            .content_type("text/plain")
+            .body(message.to_string()),
+    }
 }
 
 /// Actix web uses `ResponseError` for conversion of errors to a response
 impl ResponseError for FlounderError {
-    fn error_response(&self) -> HttpResponse {
+    fn status_code(&self) -> StatusCode {
         match self {
-            FlounderError::UnauthorizedError => HttpResponse::Forbidden().finish(),
-            _ => HttpResponse::InternalServerError().finish(),
+            FlounderError::NotFound => StatusCode::NOT_FOUND,
+            FlounderError::Unauthorized => StatusCode::FORBIDDEN,
+            FlounderError::Validation(_) | FlounderError::Multipart(_) => StatusCode::BAD_REQUEST,
+            FlounderError::QuotaExceeded { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            FlounderError::Pool(_) => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
+
+    fn error_response(&self) -> HttpResponse {
+        self.log();
+        error_page(self.status_code(), &self.to_string())
+    }
 }
 
 impl From<rusqlite::Error> for FlounderError {
-    fn from(_: rusqlite::Error) -> FlounderError {
-        FlounderError::MiscError
+    fn from(e: rusqlite::Error) -> FlounderError {
+        FlounderError::Db(e)
     }
 }
 
-impl From<Error> for FlounderError {
-    fn from(_: Error) -> FlounderError {
-        FlounderError::MiscError
+impl From<r2d2::Error> for FlounderError {
+    fn from(e: r2d2::Error) -> FlounderError {
+        FlounderError::Pool(e)
     }
 }
 
 impl From<std::io::Error> for FlounderError {
-    fn from(_: std::io::Error) -> FlounderError {
-        FlounderError::MiscError
+    fn from(e: std::io::Error) -> FlounderError {
+        FlounderError::Io(e)
+    }
+}
+
+impl From<askama::Error> for FlounderError {
+    fn from(e: askama::Error) -> FlounderError {
+        FlounderError::Template(e)
     }
 }
 
 impl From<actix_multipart::MultipartError> for FlounderError {
-    fn from(_: actix_multipart::MultipartError) -> FlounderError {
-        FlounderError::MiscError
+    fn from(e: actix_multipart::MultipartError) -> FlounderError {
+        FlounderError::Multipart(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_statuses() {
+        let not_found = FlounderError::NotFound;
+        assert_eq!(not_found.status_code(), StatusCode::NOT_FOUND);
+        assert_eq!(not_found.gemini_status(), 51);
+        assert_eq!(FlounderError::Unauthorized.gemini_status(), 59);
+        let db = FlounderError::from(rusqlite::Error::QueryReturnedNoRows);
+        assert_eq!(db.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(db.gemini_status(), 40);
+        assert!(db.source().is_some());
+        assert_eq!(db.form_errors(), None);
+        let quota = FlounderError::QuotaExceeded { quota: 1024 };
+        assert_eq!(quota.form_errors().unwrap().len(), 1);
     }
 }
@@ -0,0 +1,223 @@
+/// Atom feeds and gemfeeds generated from users' gmi files
+/// see https://tools.ietf.org/html/rfc4287 and gemini://gemini.circumlunar.space/docs/companion/subscription.gmi
+use crate::utils::mime_type;
+use chrono::{TimeZone, Utc};
+use rusqlite::Connection;
+
+pub static ATOM_PATH: &str = "atom.xml";
+pub static GEMFEED_PATH: &str = "gemfeed.gmi";
+// Served with a 404 for anything on the capsule that doesn't exist, when the user has one
+pub static NOT_FOUND_PAGE: &str = "404.gmi";
+// Server-wide feeds, on the main host
+pub static UPDATES_ATOM_PATH: &str = "updates.atom";
+pub static UPDATES_GEMINI_PATH: &str = "updates.gmi";
+pub static UPDATES_LIMIT: u32 = 64;
+
+pub struct FeedEntry {
+    pub username: String,
+    pub user_path: String,
+    pub title: String,
+    pub updated: i64,
+    pub excerpt: String,
+}
+
+/// First heading in a gmi file, if there is one
+pub fn gmi_title(gmi: &str) -> Option<&str> {
+    gmi.lines()
+        .find(|line| line.starts_with('#'))
+        .map(|line| line.trim_start_matches('#').trim())
+        .filter(|title| !title.is_empty())
+}
+
+/// The first few lines of actual text, skipping headings, links and preformatted blocks
+pub fn gmi_excerpt(gmi: &str, max_chars: usize) -> String {
+    let mut excerpt = String::new();
+    let mut preformatted = false;
+    for line in gmi.lines() {
+        if line.starts_with("```") {
+            preformatted = !preformatted;
+            continue;
+        }
+        if preformatted || line.starts_with('#') || line.starts_with("=>") || line.trim().is_empty()
+        {
+            continue;
+        }
+        if !excerpt.is_empty() {
+            excerpt.push(' ');
+        }
+        excerpt.push_str(line.trim_start_matches(|c| c == '*' || c == '>').trim());
+        if excerpt.chars().count() > max_chars {
+            let mut truncated: String = excerpt.chars().take(max_chars).collect();
+            truncated.push('…');
+            return truncated;
+        }
+    }
+    excerpt
+}
+
+/// Every gmi page of a user's, newest first. The home page and the generated feed are left out
+pub fn user_entries(conn: &Connection, username: &str) -> Result<Vec<FeedEntry>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+        SELECT file.user_path, file.full_path, file.updated_at
+        FROM file
+        JOIN user
+        ON file.user_id = user.id
+        WHERE user.username = (?1)
+        AND (file.user_path LIKE '%.gmi' OR file.user_path LIKE '%.gemini')
+        AND file.user_path NOT IN ('index.gmi', (?2))
+        ORDER BY file.updated_at DESC
+        "#,
+    )?;
+    let rows = stmt.query_map(&[username, NOT_FOUND_PAGE], |row| {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+    })?;
+    let mut entries = vec![];
+    for row in rows {
+        let (user_path, full_path, updated): (String, String, i64) = row?;
+        entries.push(entry_from_file(username, user_path, &full_path, updated));
+    }
+    Ok(entries)
+}
+
+/// The most recently updated files across the whole server, like the home page shows
+pub fn recent_entries(conn: &Connection, limit: u32) -> Result<Vec<FeedEntry>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+        SELECT user.username, file.user_path, file.full_path, file.updated_at
+        FROM file
+        JOIN user
+        ON file.user_id = user.id
+        WHERE user.pending = 0
+        ORDER BY file.updated_at DESC
+        LIMIT (?)
+        "#,
+    )?;
+    let rows = stmt.query_map(&[limit], |row| {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+    })?;
+    let mut entries = vec![];
+    for row in rows {
+        let (username, user_path, full_path, updated): (String, String, String, i64) = row?;
+        entries.push(entry_from_file(&username, user_path, &full_path, updated));
+    }
+    Ok(entries)
+}
+
+/// Title and excerpt come from the file when it's gemtext; anything else is just its path
+pub fn entry_from_file(
+    username: &str,
+    user_path: String,
+    full_path: &str,
+    updated: i64,
+) -> FeedEntry {
+    let gmi = if mime_type(&user_path) == "text/gemini" {
+        std::fs::read_to_string(full_path).unwrap_or_default()
+    } else {
+        String::new()
+    };
+    FeedEntry {
+        username: username.to_string(),
+        title: gmi_title(&gmi).unwrap_or(&user_path).to_string(),
+        excerpt: gmi_excerpt(&gmi, 280),
+        user_path: user_path,
+        updated: updated,
+    }
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn rfc3339(epoch: i64) -> String {
+    Utc.timestamp(epoch, 0).to_rfc3339()
+}
+
+/// link turns an entry into an absolute URL
+pub fn atom_feed(
+    title: &str,
+    feed_url: &str,
+    site_url: &str,
+    entries: &[FeedEntry],
+    link: impl Fn(&FeedEntry) -> String,
+) -> String {
+    let updated = entries.iter().map(|e| e.updated).max().unwrap_or(0);
+    let mut feed = format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <title>{}</title>
+  <id>{}</id>
+  <link href="{}" rel="self"/>
+  <link href="{}"/>
+  <updated>{}</updated>
+"#,
+        xml_escape(title),
+        xml_escape(feed_url),
+        xml_escape(feed_url),
+        xml_escape(site_url),
+        rfc3339(updated)
+    );
+    for entry in entries {
+        let url = link(entry);
+        feed.push_str(&format!(
+            r#"  <entry>
+    <title>{}</title>
+    <id>{}</id>
+    <link href="{}"/>
+    <updated>{}</updated>
+    <author><name>{}</name></author>
+    <summary>{}</summary>
+  </entry>
+"#,
+            xml_escape(&entry.title),
+            xml_escape(&url),
+            xml_escape(&url),
+            rfc3339(entry.updated),
+            xml_escape(&entry.username),
+            xml_escape(&entry.excerpt)
+        ));
+    }
+    feed.push_str("</feed>\n");
+    feed
+}
+
+/// A gemtext page of `=> link YYYY-MM-DD title` lines, which Gemini clients can subscribe to
+pub fn gemfeed(title: &str, entries: &[FeedEntry], link: impl Fn(&FeedEntry) -> String) -> String {
+    let mut feed = format!("# {}\n\n", title);
+    for entry in entries {
+        feed.push_str(&format!(
+            "=> {} {} {}\n",
+            link(entry),
+            Utc.timestamp(entry.updated, 0).format("%Y-%m-%d"),
+            entry.title
+        ));
+    }
+    feed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gmi_title() {
+        assert_eq!(gmi_title("some text\n## My Post \nmore"), Some("My Post"));
+        assert_eq!(gmi_title("no headings"), None);
+    }
+
+    #[test]
+    fn test_gemfeed() {
+        let entries = vec![FeedEntry {
+            username: "alice".to_string(),
+            user_path: "blog/post.gmi".to_string(),
+            title: "My Post".to_string(),
+            updated: 1600000000,
+            excerpt: "".to_string(),
+        }];
+        let feed = gemfeed("alice", &entries, |e| e.user_path.clone());
+        assert_eq!(feed, "# alice\n\n=> blog/post.gmi 2020-09-13 My Post\n");
+    }
+}
@@ -1 +1,428 @@
-/// Client for Gemini proxying
+/// Native Gemini server, serving user capsules straight out of the file directory
+/// https://gemini.circumlunar.space/docs/specification.html
+use crate::db::{self, Pool};
+use crate::error::FlounderError;
+use crate::feed;
+use crate::utils::{
+    capsule_for_host, mime_type, normalize_request_path, ssl_acceptor, to_io_error,
+};
+use crate::Config;
+use openssl::ssl::{NameType, SslAcceptor, SslStream};
+use percent_encoding::percent_decode_str;
+use rusqlite::Connection;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use url::Url;
+
+// Spec says requests are at most 1024 bytes plus CRLF
+const MAX_REQUEST_LENGTH: usize = 1026;
+// Connections served at once. Past this, new ones wait to be accepted
+const MAX_CONNECTIONS: usize = 64;
+// From accepting a connection to having its request, handshake included
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Response {
+    pub status: u8,
+    pub meta: String,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn success(mime: &str, body: Vec<u8>) -> Self {
+        Self {
+            status: 20,
+            meta: mime.to_string(),
+            body: body,
+        }
+    }
+
+    pub fn redirect(location: &str) -> Self {
+        Self {
+            status: 31,
+            meta: location.to_string(),
+            body: vec![],
+        }
+    }
+
+    pub fn not_found() -> Self {
+        Self {
+            status: 51,
+            meta: "Not found".to_string(),
+            body: vec![],
+        }
+    }
+
+    pub fn proxy_refused() -> Self {
+        Self {
+            status: 53,
+            meta: "This server does not serve that host".to_string(),
+            body: vec![],
+        }
+    }
+
+    pub fn bad_request(reason: &str) -> Self {
+        Self {
+            status: 59,
+            meta: reason.to_string(),
+            body: vec![],
+        }
+    }
+
+    pub fn error(e: &FlounderError) -> Self {
+        Self {
+            status: e.gemini_status(),
+            meta: e.to_string(),
+            body: vec![],
+        }
+    }
+}
+
+/// What a request path points at
+#[derive(Debug, PartialEq)]
+enum Route {
+    Root,
+    Updates,
+    UserHome(String),
+    UserFile(String, String),
+    AddSlash(String),
+}
+
+/// On the main host this mirrors the HTTP routes: /user/{username}/{user_file_path}
+/// On a capsule host (<username>.<server_name>) paths map straight to the user's files
+fn route(url: &Url, capsule: Option<&str>) -> Option<Route> {
+    let segments: Vec<String> = url
+        .path_segments()
+        .map(|s| {
+            s.map(|s| percent_decode_str(s).decode_utf8_lossy().to_string())
+                .collect()
+        })
+        .unwrap_or_default();
+    let segments: Vec<&str> = segments.iter().map(|s| s.as_str()).collect();
+    let user_file = |username: &str, rest: &[&str]| {
+        normalize_request_path(&rest.join("/"))
+            .map(|user_path| Route::UserFile(username.to_string(), user_path))
+    };
+    if let Some(username) = capsule {
+        return match segments.as_slice() {
+            [] | [""] => Some(Route::UserHome(username.to_string())),
+            rest => user_file(username, rest),
+        };
+    }
+    match segments.as_slice() {
+        [] | [""] => Some(Route::Root),
+        [path] if *path == feed::UPDATES_GEMINI_PATH => Some(Route::Updates),
+        ["user", username] if !username.is_empty() => {
+            Some(Route::AddSlash(format!("{}/", url.path())))
+        }
+        ["user", username, ""] => Some(Route::UserHome(username.to_string())),
+        ["user", username, rest @ ..] => user_file(username, rest),
+        _ => None,
+    }
+}
+
+fn serve_file(
+    conn: &Connection,
+    username: &str,
+    user_path: &str,
+) -> Result<Response, FlounderError> {
+    match db::full_path(conn, username, user_path)? {
+        Some(full_path) => match std::fs::read(&full_path) {
+            Ok(data) => Ok(Response::success(mime_type(user_path), data)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Response::not_found()),
+            Err(e) => Err(e.into()),
+        },
+        None => Ok(Response::not_found()),
+    }
+}
+
+fn serve_root(conn: &Connection, config: &Config) -> Result<Response, FlounderError> {
+    let server_host = config.server_host();
+    let mut page = format!(
+        "# 🐟Flounder: {}\n\n=> /{} Recently updated pages\n\n## All users:\n",
+        server_host,
+        feed::UPDATES_GEMINI_PATH
+    );
+    for username in db::usernames(conn)? {
+        page.push_str(&format!(
+            "=> gemini://{}.{}/ {}\n",
+            username, server_host, username
+        ));
+    }
+    Ok(Response::success("text/gemini", page.into_bytes()))
+}
+
+fn serve_updates(conn: &Connection, config: &Config) -> Result<Response, FlounderError> {
+    let server_host = config.server_host();
+    let mut entries = feed::recent_entries(conn, feed::UPDATES_LIMIT)?;
+    for entry in entries.iter_mut() {
+        entry.title = format!("{}: {}", entry.username, entry.title);
+    }
+    let gmi = feed::gemfeed(
+        &format!("Recently updated on {}", server_host),
+        &entries,
+        |e| format!("gemini://{}.{}/{}", e.username, server_host, e.user_path),
+    );
+    Ok(Response::success("text/gemini", gmi.into_bytes()))
+}
+
+fn serve_atom_feed(
+    conn: &Connection,
+    config: &Config,
+    username: &str,
+) -> Result<Response, FlounderError> {
+    if !db::user_exists(conn, username)? {
+        return Ok(Response::not_found());
+    }
+    let entries = feed::user_entries(conn, username)?;
+    let site_url = format!("gemini://{}.{}/", username, config.server_host());
+    let atom = feed::atom_feed(
+        username,
+        &format!("{}{}", site_url, feed::ATOM_PATH),
+        &site_url,
+        &entries,
+        |e| format!("{}{}", site_url, e.user_path),
+    );
+    Ok(Response::success("application/atom+xml", atom.into_bytes()))
+}
+
+fn handle_request(
+    request: &str,
+    sni: Option<&str>,
+    conn: &Connection,
+    config: &Config,
+) -> Result<Response, FlounderError> {
+    let url = match Url::parse(request) {
+        Ok(url) => url,
+        Err(_) => return Ok(Response::bad_request("Invalid URL")),
+    };
+    if url.scheme() != "gemini" {
+        return Ok(Response::proxy_refused());
+    }
+    let host = match url.host_str() {
+        Some(host) => host.to_lowercase(),
+        None => return Ok(Response::bad_request("Missing host")),
+    };
+    // Don't let a client handshake for one capsule and then ask for another
+    if let Some(sni) = sni {
+        if !sni.eq_ignore_ascii_case(&host) {
+            return Ok(Response::proxy_refused());
+        }
+    }
+    let capsule = match capsule_for_host(&host, &config.server_host()) {
+        Some(capsule) => capsule,
+        None => return Ok(Response::proxy_refused()),
+    };
+    match route(&url, capsule) {
+        Some(Route::Root) => serve_root(conn, config),
+        Some(Route::Updates) => serve_updates(conn, config),
+        Some(Route::AddSlash(location)) => Ok(Response::redirect(&location)),
+        Some(Route::UserHome(username)) => serve_file(conn, &username, "index.gmi"),
+        Some(Route::UserFile(username, user_path)) if user_path == feed::ATOM_PATH => {
+            serve_atom_feed(conn, config, &username)
+        }
+        Some(Route::UserFile(username, user_path)) if user_path == feed::GEMFEED_PATH => {
+            if !db::user_exists(conn, &username)? {
+                return Ok(Response::not_found());
+            }
+            let entries = feed::user_entries(conn, &username)?;
+            let gmi = feed::gemfeed(&username, &entries, |e| e.user_path.clone());
+            Ok(Response::success("text/gemini", gmi.into_bytes()))
+        }
+        Some(Route::UserFile(username, user_path)) => serve_file(conn, &username, &user_path),
+        None => Ok(Response::not_found()),
+    }
+}
+
+/// Read the request line, up to and including CRLF
+fn read_request(stream: &mut SslStream<TcpStream>) -> io::Result<Option<String>> {
+    let mut request = vec![];
+    let mut buf = [0; MAX_REQUEST_LENGTH];
+    while !request.ends_with(b"\r\n") {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+        if request.len() > MAX_REQUEST_LENGTH {
+            return Ok(None);
+        }
+    }
+    if !request.ends_with(b"\r\n") {
+        return Ok(None);
+    }
+    request.truncate(request.len() - 2);
+    Ok(String::from_utf8(request).ok())
+}
+
+/// Per worker, the connection it's reading a request from and when that has to be done by.
+/// Read timeouts alone would let a client sending a byte at a time hold a worker forever
+#[derive(Clone)]
+struct Deadlines(Arc<Mutex<Vec<Option<(Instant, TcpStream)>>>>);
+
+impl Deadlines {
+    fn new(workers: usize) -> Self {
+        Deadlines(Arc::new(Mutex::new((0..workers).map(|_| None).collect())))
+    }
+
+    fn set(&self, worker: usize, stream: &TcpStream) -> io::Result<()> {
+        let stream = stream.try_clone()?;
+        self.0.lock().unwrap()[worker] = Some((Instant::now() + REQUEST_TIMEOUT, stream));
+        Ok(())
+    }
+
+    fn clear(&self, worker: usize) {
+        self.0.lock().unwrap()[worker] = None;
+    }
+
+    /// Blocking. Shuts down connections that run past their deadline, which fails the read
+    /// they're stuck in
+    fn enforce(&self) {
+        loop {
+            thread::sleep(Duration::from_secs(1));
+            let now = Instant::now();
+            for slot in self.0.lock().unwrap().iter_mut() {
+                if slot
+                    .as_ref()
+                    .map_or(false, |(deadline, _)| *deadline <= now)
+                {
+                    if let Some((_, stream)) = slot.take() {
+                        stream.shutdown(Shutdown::Both).ok();
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn handle_connection(
+    stream: TcpStream,
+    acceptor: &SslAcceptor,
+    pool: &Pool,
+    config: &Config,
+    deadlines: &Deadlines,
+    worker: usize,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(Duration::from_secs(10)))?;
+    deadlines.set(worker, &stream)?;
+    let accepted = acceptor
+        .accept(stream)
+        .map_err(to_io_error)
+        .and_then(|mut stream| Ok((read_request(&mut stream)?, stream)));
+    deadlines.clear(worker);
+    let (request, mut stream) = accepted?;
+    let sni = stream
+        .ssl()
+        .servername(NameType::HOST_NAME)
+        .map(|s| s.to_string());
+    let response = match request {
+        Some(request) => {
+            let response = pool
+                .get()
+                .map_err(FlounderError::from)
+                .and_then(|conn| handle_request(&request, sni.as_deref(), &conn, config));
+            // the client still gets a status line when something goes wrong on our end
+            response.unwrap_or_else(|e| {
+                e.log();
+                Response::error(&e)
+            })
+        }
+        None => Response::bad_request("Malformed request"),
+    };
+    stream.write_all(format!("{} {}\r\n", response.status, response.meta).as_bytes())?;
+    stream.write_all(&response.body)?;
+    stream.shutdown().ok();
+    Ok(())
+}
+
+/// Blocking -- run this in its own thread. Connections are handed to MAX_CONNECTIONS workers
+pub fn run_server(config: Config, pool: Pool) -> io::Result<()> {
+    let acceptor = ssl_acceptor(&config.gemini_cert_path, &config.gemini_key_path)?;
+    let acceptor = Arc::new(acceptor.build());
+    let config = Arc::new(config);
+    let deadlines = Deadlines::new(MAX_CONNECTIONS);
+    let (sender, receiver) = mpsc::sync_channel::<TcpStream>(MAX_CONNECTIONS);
+    let receiver = Arc::new(Mutex::new(receiver));
+    for worker in 0..MAX_CONNECTIONS {
+        let acceptor = acceptor.clone();
+        let pool = pool.clone();
+        let config = config.clone();
+        let deadlines = deadlines.clone();
+        let receiver = receiver.clone();
+        thread::spawn(move || loop {
+            let next = receiver.lock().unwrap().recv();
+            let stream = match next {
+                Ok(stream) => stream,
+                Err(_) => return,
+            };
+            if let Err(e) = handle_connection(stream, &acceptor, &pool, &config, &deadlines, worker)
+            {
+                log::warn!("Gemini connection error: {}", e);
+            }
+        });
+    }
+    thread::spawn(move || deadlines.enforce());
+
+    let listener = TcpListener::bind(&config.gemini_bind)?;
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(_) => continue,
+        };
+        // blocks while every worker is busy and the queue is full
+        if sender.send(stream).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route() {
+        let route_of = |s| route(&Url::parse(s).unwrap(), None);
+        assert_eq!(route_of("gemini://flounder.local/"), Some(Route::Root));
+        assert_eq!(
+            route_of("gemini://flounder.local/user/alice"),
+            Some(Route::AddSlash("/user/alice/".to_string()))
+        );
+        assert_eq!(
+            route_of("gemini://flounder.local/user/alice/"),
+            Some(Route::UserHome("alice".to_string()))
+        );
+        assert_eq!(
+            route_of("gemini://flounder.local/user/alice/my%20page.gmi"),
+            Some(Route::UserFile(
+                "alice".to_string(),
+                "my page.gmi".to_string()
+            ))
+        );
+        assert_eq!(route_of("gemini://flounder.local/static/style.css"), None);
+    }
+
+    #[test]
+    fn test_capsule_route() {
+        let route_of = |s| route(&Url::parse(s).unwrap(), Some("alice"));
+        assert_eq!(
+            route_of("gemini://alice.flounder.local"),
+            Some(Route::UserHome("alice".to_string()))
+        );
+        assert_eq!(
+            route_of("gemini://alice.flounder.local/"),
+            Some(Route::UserHome("alice".to_string()))
+        );
+        assert_eq!(
+            route_of("gemini://alice.flounder.local/twtxt.txt"),
+            Some(Route::UserFile(
+                "alice".to_string(),
+                "twtxt.txt".to_string()
+            ))
+        );
+    }
+}
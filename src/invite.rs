@@ -0,0 +1,187 @@
+/// Invite codes, for registration = "invite" and for skipping the approval queue
+use crate::session::random_hex;
+use rusqlite::{params, Connection, OptionalExtension};
+
+pub struct Invite {
+    pub code: String,
+    pub used_by: Option<String>,
+    pub revoked: bool,
+}
+
+/// created_by is None for invites made with `flounder admin create-invite`
+pub fn create(conn: &Connection, created_by: Option<i64>) -> rusqlite::Result<String> {
+    let code = random_hex(8);
+    conn.execute(
+        "INSERT INTO invite (code, created_by) VALUES (?1, ?2)",
+        params![code, created_by],
+    )?;
+    Ok(code)
+}
+
+/// The user's invites, newest first
+pub fn list(conn: &Connection, user_id: &str) -> rusqlite::Result<Vec<Invite>> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+        SELECT invite.code, user.username, invite.revoked_at IS NOT NULL
+        FROM invite LEFT JOIN user
+        ON invite.used_by = user.id
+        WHERE invite.created_by = (?1)
+        ORDER BY invite.created_at DESC, invite.rowid DESC
+        "#,
+    )?;
+    let res = stmt.query_map(&[user_id], |row| {
+        Ok(Invite {
+            code: row.get(0)?,
+            used_by: row.get(1)?,
+            revoked: row.get(2)?,
+        })
+    })?;
+    res.collect()
+}
+
+/// How many of the user's codes could still be used
+pub fn outstanding(conn: &Connection, user_id: &str) -> rusqlite::Result<u32> {
+    conn.query_row(
+        r#"
+        SELECT COUNT(*) FROM invite
+        WHERE created_by = (?1) AND used_by IS NULL AND revoked_at IS NULL
+        "#,
+        &[user_id],
+        |row| row.get(0),
+    )
+}
+
+/// Only unused codes of the user's own can be revoked
+pub fn revoke(conn: &Connection, user_id: &str, code: &str) -> rusqlite::Result<bool> {
+    let updated = conn.execute(
+        r#"
+        UPDATE invite SET revoked_at = strftime('%s', 'now')
+        WHERE code = (?1) AND created_by = (?2) AND used_by IS NULL AND revoked_at IS NULL
+        "#,
+        &[code, user_id],
+    )?;
+    Ok(updated > 0)
+}
+
+pub fn is_usable(conn: &Connection, code: &str) -> rusqlite::Result<bool> {
+    let found: Option<i64> = conn
+        .query_row(
+            "SELECT 1 FROM invite WHERE code = (?1) AND used_by IS NULL AND revoked_at IS NULL",
+            &[code],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(found.is_some())
+}
+
+/// Marks the code used by a new account. False if it was used or revoked in the meantime.
+/// Each code works once, and used_by is kept along with created_by, so any account can be
+/// traced back through the people who invited it
+pub fn redeem(conn: &Connection, code: &str, user_id: i64) -> rusqlite::Result<bool> {
+    let updated = conn.execute(
+        r#"
+        UPDATE invite SET used_by = (?2), used_at = strftime('%s', 'now')
+        WHERE code = (?1) AND used_by IS NULL AND revoked_at IS NULL
+        "#,
+        params![code, user_id],
+    )?;
+    Ok(updated > 0)
+}
+
+/// Who invited the user, who invited them, and so on, nearest first
+pub fn inviters(conn: &Connection, user_id: i64) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+        WITH RECURSIVE chain(user_id, depth) AS (
+            SELECT (?1), 0
+            UNION ALL
+            SELECT invite.created_by, chain.depth + 1
+            FROM invite JOIN chain
+            ON invite.used_by = chain.user_id
+            WHERE invite.created_by IS NOT NULL
+        )
+        SELECT user.username FROM chain JOIN user
+        ON chain.user_id = user.id
+        WHERE chain.depth > 0
+        ORDER BY chain.depth
+        "#,
+    )?;
+    let res = stmt.query_map(&[user_id], |row| row.get(0))?;
+    res.collect()
+}
+
+/// Everyone the user invited, and everyone they invited, as (depth, username) in tree order
+pub fn invitees(conn: &Connection, user_id: i64) -> rusqlite::Result<Vec<(usize, String)>> {
+    let mut tree = vec![];
+    add_invitees(conn, user_id, 1, &mut tree)?;
+    Ok(tree)
+}
+
+fn add_invitees(
+    conn: &Connection,
+    user_id: i64,
+    depth: usize,
+    tree: &mut Vec<(usize, String)>,
+) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+        SELECT user.id, user.username FROM invite JOIN user
+        ON invite.used_by = user.id
+        WHERE invite.created_by = (?1)
+        ORDER BY invite.used_at, user.id
+        "#,
+    )?;
+    let res = stmt.query_map(&[user_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    let children: Vec<(i64, String)> = res.collect::<Result<_, _>>()?;
+    // used_by is unique and accounts exist before they invite anyone, so this can't loop
+    for (child_id, username) in children {
+        tree.push((depth, username));
+        add_invitees(conn, child_id, depth + 1, tree)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invite_tree() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        crate::db::migrate(&mut conn).unwrap();
+        for (id, name) in [(1, "alice"), (2, "bob"), (3, "carol"), (4, "dave")].iter() {
+            conn.execute(
+                "INSERT INTO user (id, username) VALUES (?1, ?2)",
+                params![id, name],
+            )
+            .unwrap();
+        }
+        let root = create(&conn, None).unwrap();
+        assert!(redeem(&conn, &root, 1).unwrap());
+        assert!(!redeem(&conn, &root, 2).unwrap());
+        let code = create(&conn, Some(1)).unwrap();
+        assert!(redeem(&conn, &code, 2).unwrap());
+        let code = create(&conn, Some(2)).unwrap();
+        assert!(redeem(&conn, &code, 3).unwrap());
+        let code = create(&conn, Some(1)).unwrap();
+        assert!(redeem(&conn, &code, 4).unwrap());
+
+        let revoked = create(&conn, Some(1)).unwrap();
+        assert_eq!(outstanding(&conn, "1").unwrap(), 1);
+        assert!(!revoke(&conn, "2", &revoked).unwrap());
+        assert!(revoke(&conn, "1", &revoked).unwrap());
+        assert!(!is_usable(&conn, &revoked).unwrap());
+        assert!(!redeem(&conn, &revoked, 4).unwrap());
+        assert_eq!(list(&conn, "1").unwrap().len(), 3);
+
+        assert_eq!(inviters(&conn, 3).unwrap(), vec!["bob", "alice"]);
+        assert_eq!(
+            invitees(&conn, 1).unwrap(),
+            vec![
+                (1, "bob".to_string()),
+                (2, "carol".to_string()),
+                (1, "dave".to_string())
+            ]
+        );
+    }
+}
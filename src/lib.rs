@@ -5,89 +5,126 @@ use actix_files as fs; // TODO optional
 use actix_identity::{CookieIdentityPolicy, Identity, IdentityService};
 use actix_multipart::Multipart;
 use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
-use actix_web::error as actix_error;
+use actix_web::dev::{Service, ServiceRequest};
+use actix_web::http::{StatusCode, Uri};
 use actix_web::middleware::{Logger, NormalizePath};
 use actix_web::FromRequest;
-use actix_web::{web, App, Error, HttpRequest, HttpResponse, HttpServer};
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
 use bcrypt;
 use env_logger;
 use env_logger::Env;
 use error::FlounderError;
 use futures::{StreamExt, TryStreamExt};
 use gmi2html;
-use rusqlite::{Connection, Result, NO_PARAMS};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use rusqlite::{Connection, Result};
 use serde::Deserialize;
 use std::ffi::OsStr;
 use std::io::Write;
 use std::path::Path;
 use std::str;
-use std::sync::Mutex;
 use std::time::Duration;
 use utils::*;
 
+pub mod admin;
 mod client;
+mod config;
+mod csrf;
+pub mod db;
 mod error;
+mod feed;
+mod gemini;
+mod invite;
+mod mailer;
+mod password;
+mod proxy;
+mod session;
 mod templates;
 mod twtxt;
 mod utils;
+mod verification;
 
+pub use config::{Config, Registration};
 use templates::*;
 
 static BASE_INDEX: &[u8] = include_bytes!("baseIndex.gmi");
 
-type DbConn = web::Data<Mutex<Connection>>;
-
-#[derive(Deserialize, Clone)]
-struct Config {
-    db_path: String,
-    file_directory: String,
-    tls_enabled: bool,
-    server_name: String,
-    serve_all_content: bool, // Don't use nginx for anything. In production probably we wanna use nginx for static files
-    // Not ready for open registration yet -- use this
-    static_path: String,
-    proxy_url: String,
-}
+type DbConn = web::Data<db::Pool>;
 
 #[derive(Deserialize)]
 struct LoginForm {
     username: String,
     password: String,
+    csrf_token: String,
 }
 
-// hacking actix_identity to store user and id
-// id, then name
-fn parse_identity(id: String) -> (String, String) {
-    // TODO fix this shit
-    let mut split = id.split_whitespace();
-    (
-        split.next().unwrap().to_string(),
-        split.next().unwrap().to_string(),
-    )
+#[derive(Deserialize)]
+struct CsrfForm {
+    csrf_token: String,
+}
+
+/// (user id, username) for the session in the identity cookie. Unauthorized only when there
+/// is no live session; database and pool errors come through as themselves
+fn session_user(id: &Identity, conn: &DbConn) -> Result<(String, String), FlounderError> {
+    let session_id = id.identity().ok_or(FlounderError::Unauthorized)?;
+    let conn = conn.get()?;
+    session::lookup(&conn, &session_id)?.ok_or(FlounderError::Unauthorized)
+}
+
+/// The token to render into forms, if the identity cookie holds a live session
+fn session_csrf_token(id: &Identity, conn: &DbConn) -> Result<Option<String>, FlounderError> {
+    match id.identity() {
+        Some(session_id) => Ok(session::csrf_token(&*conn.get()?, &session_id)?),
+        None => Ok(None),
+    }
+}
+
+/// Every POST behind a login checks its form's token with this
+fn check_csrf(id: &Identity, conn: &DbConn, token: &str) -> Result<(), FlounderError> {
+    match session_csrf_token(id, conn)? {
+        Some(expected) if csrf::tokens_match(&expected, token) => Ok(()),
+        _ => Err(FlounderError::Unauthorized),
+    }
 }
 
 // TODO user login auth
 async fn login(
     id: Identity,
+    r: HttpRequest,
     conn: DbConn,
     form: web::Form<LoginForm>,
+    config: web::Data<Config>,
 ) -> Result<HttpResponse, FlounderError> {
-    let conn = conn.lock().unwrap();
-    let mut stmt = conn.prepare_cached(
-        r#"
-        SELECT id, password_hash from user 
-        WHERE user.username = (?)
-        "#,
-    )?;
+    if !csrf::check_anonymous(&r, &form.csrf_token) {
+        return Err(FlounderError::Unauthorized);
+    }
+    let conn = conn.get()?;
     // user does not exist etc
-    let (user_id, password_hash): (u32, String) = stmt
-        .query_row(&[&form.username], |row| {
-            Ok((row.get(0).unwrap(), row.get(1).unwrap()))
-        })
-        .unwrap_or((0, "notahash".to_string())); // TODO make less awk
-    if let Ok(true) = bcrypt::verify(&form.password, &password_hash) {
+    let user = db::login_user(&conn, &form.username)?.unwrap_or(db::LoginUser {
+        id: 0,
+        password_hash: None,
+        pending: false,
+        locked: false,
+    });
+    let password_hash = user.password_hash.as_deref().unwrap_or("notahash");
+    let password_ok = bcrypt::verify(&form.password, password_hash).unwrap_or(false);
+    let refusal = if user.locked {
+        Some("This account is locked. Contact an admin.")
+    } else if user.pending {
+        Some("Your account is waiting for an admin to approve it.")
+    } else {
+        None
+    };
+    if let (true, Some(refusal)) = (password_ok, refusal) {
+        return LoginTemplate {
+            errors: vec![refusal],
+            csrf_token: &form.csrf_token,
+        }
+        .into_response();
+    }
+    if password_ok {
         // flash?
-        id.remember(format!("{} {}", user_id.to_string(), form.username)); // awk
+        id.remember(session::create(&conn, user.id, &config)?);
         Ok(HttpResponse::Found()
             .header("Location", "/my_site")
             .finish()) // TODO
@@ -95,115 +132,402 @@ async fn login(
         // render login page w errors
         let template = LoginTemplate {
             errors: vec!["Invalid username or password!"],
+            csrf_token: &form.csrf_token,
         };
         return template.into_response();
     }
 }
 
-async fn logout(id: Identity) -> Result<HttpResponse, Error> {
+async fn logout(
+    id: Identity,
+    conn: DbConn,
+    form: web::Form<CsrfForm>,
+) -> Result<HttpResponse, FlounderError> {
+    // an expired session has nothing left to protect
+    if let Some(expected) = session_csrf_token(&id, &conn)? {
+        if !csrf::tokens_match(&expected, &form.csrf_token) {
+            return Err(FlounderError::Unauthorized);
+        }
+        session::delete(&*conn.get()?, &id.identity().unwrap_or_default())?;
+    }
     id.forget();
     Ok(HttpResponse::Found().header("Location", "/").finish()) // TODO
 }
 
+/// Ends every session of the user's, on every device
+async fn logout_all(
+    id: Identity,
+    conn: DbConn,
+    form: web::Form<CsrfForm>,
+) -> Result<HttpResponse, FlounderError> {
+    let (user_id, _) = session_user(&id, &conn)?;
+    check_csrf(&id, &conn, &form.csrf_token)?;
+    session::delete_all(&*conn.get()?, &user_id)?;
+    id.forget();
+    Ok(HttpResponse::Found().header("Location", "/").finish())
+}
+
+#[derive(Deserialize)]
+struct ChangePasswordForm {
+    current_password: String,
+    password: String,
+    password2: String,
+    csrf_token: String,
+}
+
+async fn change_password_page(id: Identity, conn: DbConn) -> Result<HttpResponse, FlounderError> {
+    session_user(&id, &conn)?;
+    let csrf_token = session_csrf_token(&id, &conn)?.unwrap_or_default();
+    ChangePasswordTemplate {
+        logged_in: true,
+        csrf_token: &csrf_token,
+        errors: vec![],
+    }
+    .into_response()
+}
+
+async fn change_password(
+    id: Identity,
+    conn: DbConn,
+    form: web::Form<ChangePasswordForm>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, FlounderError> {
+    let (user_id, _) = session_user(&id, &conn)?;
+    check_csrf(&id, &conn, &form.csrf_token)?;
+    let conn = conn.get()?;
+    let password_hash = db::password_hash(&conn, &user_id)?;
+    let mut errors = vec![];
+    if !bcrypt::verify(&form.current_password, &password_hash.unwrap_or_default()).unwrap_or(false)
+    {
+        errors.push("Current password is incorrect");
+    }
+    errors.extend(password::password_errors(&form.password, &form.password2));
+    if errors.len() > 0 {
+        return ChangePasswordTemplate {
+            logged_in: true,
+            csrf_token: &form.csrf_token,
+            errors: errors,
+        }
+        .into_response();
+    }
+    password::set_password(&conn, &user_id, &form.password)?;
+    // that ended every session, this one included
+    let user_id: i64 = user_id
+        .parse()
+        .map_err(|_| FlounderError::Internal("user id is not a number"))?;
+    id.remember(session::create(&conn, user_id, &config)?);
+    Ok(HttpResponse::Found()
+        .header("Location", "/my_site")
+        .finish())
+}
+
+#[derive(Deserialize)]
+struct ForgotPasswordForm {
+    username: String, // or email
+    csrf_token: String,
+}
+
+async fn forgot_password_page(
+    r: HttpRequest,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, FlounderError> {
+    let csrf_token = csrf::anonymous_token(&r);
+    let response = ForgotPasswordTemplate {
+        csrf_token: &csrf_token,
+        mail_enabled: mailer::from_config(&config).is_some(),
+        sent: false,
+    }
+    .into_response()?;
+    Ok(csrf::with_cookie(response, &csrf_token, config.tls_enabled))
+}
+
+async fn forgot_password(
+    r: HttpRequest,
+    conn: DbConn,
+    form: web::Form<ForgotPasswordForm>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, FlounderError> {
+    if !csrf::check_anonymous(&r, &form.csrf_token) {
+        return Err(FlounderError::Unauthorized);
+    }
+    let mailer = match mailer::from_config(&config) {
+        Some(mailer) => mailer,
+        None => return forgot_password_page(r, config).await,
+    };
+    let (pool, config, username) = (
+        conn.get_ref().clone(),
+        config.clone(),
+        form.username.clone(),
+    );
+    // in the background, so known and unknown accounts take as long to answer
+    actix_rt::spawn(async move {
+        let sent = web::block(move || {
+            send_reset_email(&pool, &config, &*mailer, &username).map_err(|e| e.log())
+        });
+        sent.await.ok();
+    });
+    // the same answer either way, so this can't be used to find out who has an account
+    ForgotPasswordTemplate {
+        csrf_token: &form.csrf_token,
+        mail_enabled: true,
+        sent: true,
+    }
+    .into_response()
+}
+
+/// A reset link for the account, if there is one and it has an email address
+fn send_reset_email(
+    pool: &db::Pool,
+    config: &Config,
+    mailer: &dyn mailer::Mailer,
+    username: &str,
+) -> Result<(), FlounderError> {
+    let conn = pool.get()?;
+    let (user_id, username, to) = match db::find_account(&conn, username)? {
+        Some((user_id, username, Some(to))) => (user_id, username, to),
+        _ => return Ok(()),
+    };
+    let token = password::create_reset_token(&conn, user_id)?;
+    drop(conn);
+    let email = mailer::Email {
+        to: to,
+        subject: format!("Reset your password on {}", config.server_host()),
+        body: format!(
+            "Someone, hopefully you, asked to reset the password for {} on {}.\n\n\
+             To choose a new one, open this link within the hour:\n{}\n\n\
+             If it wasn't you, you can ignore this email.\n",
+            username,
+            config.server_host(),
+            password::reset_link(config, &token)
+        ),
+    };
+    if let Err(e) = mailer.send(&email) {
+        log::error!("Could not send password reset email: {}", e);
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct ResetPasswordForm {
+    password: String,
+    password2: String,
+    csrf_token: String,
+}
+
+async fn reset_password_page(
+    r: HttpRequest,
+    token: web::Path<String>,
+    conn: DbConn,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, FlounderError> {
+    let valid = password::reset_token_user(&*conn.get()?, &token)?.is_some();
+    let csrf_token = csrf::anonymous_token(&r);
+    let response = ResetPasswordTemplate {
+        csrf_token: &csrf_token,
+        token: &token,
+        valid: valid,
+        errors: vec![],
+    }
+    .into_response()?;
+    Ok(csrf::with_cookie(response, &csrf_token, config.tls_enabled))
+}
+
+async fn reset_password(
+    r: HttpRequest,
+    token: web::Path<String>,
+    conn: DbConn,
+    form: web::Form<ResetPasswordForm>,
+) -> Result<HttpResponse, FlounderError> {
+    if !csrf::check_anonymous(&r, &form.csrf_token) {
+        return Err(FlounderError::Unauthorized);
+    }
+    let errors = password::password_errors(&form.password, &form.password2);
+    let conn = conn.get()?;
+    if errors.is_empty() && password::redeem_reset_token(&conn, &token, &form.password)? {
+        return Ok(HttpResponse::Found().header("Location", "/login").finish());
+    }
+    // the form comes back only while the token is still good to use
+    let valid = password::reset_token_user(&conn, &token)?.is_some();
+    ResetPasswordTemplate {
+        csrf_token: &form.csrf_token,
+        token: &token,
+        valid: valid,
+        errors: errors,
+    }
+    .into_response()
+}
+
 #[derive(Deserialize)]
 struct RegisterForm {
     username: String,
     email: String,
     password: String,
     password2: String,
+    #[serde(default)]
+    invite_code: String,
+    csrf_token: String,
 }
 
 impl RegisterForm {
     fn get_errors(&self) -> Vec<&str> {
-        let mut errors = vec![];
-        if self.username.len() > 32
-            || self.username == ""
-            || &self.username.to_lowercase() == "www"
-            || &self.username.to_lowercase() == "proxy"
-        {
-            errors.push("Invalid username")
-        }
-        if !self
-            .username
-            .chars()
-            .all(|c| c.is_ascii_alphanumeric() || c == '-')
-        {
-            errors.push("Username must only contain a-z characters and hyphens");
-        }
+        let mut errors = username_errors(&self.username);
         if !self.email.contains("@") {
-            // world's dumbest email verification (we dont really use email)
+            // the real check is the link sent when verify_email is on
             errors.push("Email is invalid");
         }
-        if self.password.len() < 6 {
-            errors.push("Please use a password at least 6 characters long. Preferably longer.");
-        }
-        if self.password != self.password2 {
-            errors.push("Passwords do not match");
-        }
+        errors.extend(password::password_errors(&self.password, &self.password2));
         return errors;
     }
 }
 
 async fn register(
     id: Identity,
+    r: HttpRequest,
     conn: DbConn,
     form: web::Form<RegisterForm>,
     config: web::Data<Config>,
+    signer: web::Data<verification::LinkSigner>,
 ) -> Result<HttpResponse, FlounderError> {
+    if !csrf::check_anonymous(&r, &form.csrf_token) {
+        return Err(FlounderError::Unauthorized);
+    }
     // validate
-    let errors = form.get_errors();
+    let mut errors = form.get_errors();
+    let invite_code = form.invite_code.trim();
+    let invited = !invite_code.is_empty() && invite::is_usable(&*conn.get()?, invite_code)?;
+    if !invite_code.is_empty() && !invited {
+        errors.push("That invite code is invalid or was already used");
+    } else if config.registration == Registration::Invite && !invited {
+        errors.push("You need an invite code to register");
+    }
     if errors.len() > 0 {
-        return RegisterTemplate {
-            errors: errors,
-            server_name: &config.server_name,
-        }
-        .into_response();
+        return render_register(&config, &form.csrf_token, invite_code, errors);
     }
     let hashed_pass = bcrypt::hash(&form.password, bcrypt::DEFAULT_COST).unwrap();
-    let conn = conn.lock().unwrap();
-    let mut stmt = conn.prepare_cached(
-        r#"
-        INSERT INTO user (username, email, password_hash)
-        VALUES (?1, ?2, ?3)
-        "#,
-    )?;
-    match stmt.execute(&[&form.username.to_lowercase(), &form.email, &hashed_pass]) {
-        Ok(_) => (),
-        Err(_) => {
-            return RegisterTemplate {
-                errors: vec!["Username or email already taken"],
-                server_name: &config.server_name,
-            }
-            .into_response()
+    let username = form.username.to_lowercase();
+    // invited accounts skip the approval queue
+    let pending = config.registration == Registration::Approval && !invited;
+    let mut conn = conn.get()?;
+    // dropped without commit on the way out, so a failed redeem takes the new user with it
+    let tx = conn.transaction()?;
+    let user_id = match db::insert_user(&tx, &username, &form.email, &hashed_pass, pending)? {
+        Some(user_id) => user_id,
+        None => {
+            return render_register(
+                &config,
+                &form.csrf_token,
+                invite_code,
+                vec!["Username or email already taken"],
+            )
         }
+    };
+    // someone else may have used the code since it was checked
+    if invited && !invite::redeem(&tx, invite_code, user_id)? {
+        return render_register(
+            &config,
+            &form.csrf_token,
+            invite_code,
+            vec!["That invite code is invalid or was already used"],
+        );
     }
+    tx.commit()?;
 
-    let user_id = conn.last_insert_rowid(); // maybe this works
+    // pending accounts get theirs on approval, so there's nothing of theirs to serve until then
+    if !pending {
+        write_base_index(&conn, &config, user_id, &username)?;
+    }
 
-    // todo dont repeat;
+    if config.verify_email {
+        let link = signer.link(&config, user_id, &form.email);
+        send_email(
+            verification::email(&config, &link, &username, &form.email),
+            &config,
+        );
+    }
+    if pending {
+        return PendingTemplate {}.into_response();
+    }
+    id.remember(session::create(&conn, user_id, &config)?);
+    // redirect to my site
+    Ok(HttpResponse::Found()
+        .header("Location", "/edit/index.gmi")
+        .finish())
+}
+
+/// Every capsule starts out with the same index.gmi. Also used by `flounder admin create-user`
+/// and `approve`
+fn write_base_index(
+    conn: &Connection,
+    config: &Config,
+    user_id: i64,
+    username: &str,
+) -> std::io::Result<()> {
     let filename = "index.gmi";
     let full_path = Path::new(&config.file_directory)
-        .join(&form.username.to_lowercase())
+        .join(username)
         .join(filename); // TODO sanitize
     std::fs::create_dir_all(&full_path.parent().unwrap()).ok();
     let mut f = std::fs::File::create(&full_path)?;
-    f.write(&BASE_INDEX)?;
-    let mut stmt = conn.prepare_cached(
-        r#"
-    INSERT INTO file (user_path, user_id, full_path)
-    VALUES (?1, ?2, ?3)
-    "#,
-    )?;
-    stmt.execute(&[filename, &user_id.to_string(), &full_path.to_str().unwrap()])?;
-
-    id.remember(format!(
-        "{} {}",
-        user_id.to_string(),
-        form.username.to_lowercase()
-    )); // awk
-        // redirect to my site
+    f.write_all(&BASE_INDEX)?;
+    db::upsert_file(
+        conn,
+        filename,
+        &user_id.to_string(),
+        full_path.to_str().unwrap(),
+        BASE_INDEX.len(),
+    )
+    .map_err(to_io_error)
+}
+
+/// Sends in the background, without holding up the response. Failures are only logged:
+/// the link can be sent again from the manage page
+fn send_email(email: mailer::Email, config: &Config) {
+    if let Some(mailer) = mailer::from_config(config) {
+        actix_rt::spawn(async move {
+            if let Err(e) = web::block(move || mailer.send(&email)).await {
+                log::error!("Could not send email: {}", e);
+            }
+        });
+    }
+}
+
+async fn verify_email(
+    path: web::Path<(i64, i64, String)>,
+    conn: DbConn,
+    signer: web::Data<verification::LinkSigner>,
+) -> Result<HttpResponse, FlounderError> {
+    let (user_id, expires_at, signature) = path.into_inner();
+    let conn = conn.get()?;
+    let verified = match verification::status(&conn, &user_id.to_string())? {
+        Some((_, true)) => true,
+        Some((email, false)) => {
+            signer.check(user_id, &email, expires_at, &signature)
+                && verification::mark_verified(&conn, user_id, &email)?
+        }
+        None => false,
+    };
+    VerifyEmailTemplate { verified: verified }.into_response()
+}
+
+async fn resend_verification(
+    id: Identity,
+    conn: DbConn,
+    form: web::Form<CsrfForm>,
+    config: web::Data<Config>,
+    signer: web::Data<verification::LinkSigner>,
+) -> Result<HttpResponse, FlounderError> {
+    let (user_id, username) = session_user(&id, &conn)?;
+    check_csrf(&id, &conn, &form.csrf_token)?;
+    let status = verification::status(&*conn.get()?, &user_id)?;
+    if let (true, Some((to, false))) = (config.verify_email, status) {
+        let user_id: i64 = user_id
+            .parse()
+            .map_err(|_| FlounderError::Internal("user id is not a number"))?;
+        let link = signer.link(&config, user_id, &to);
+        send_email(verification::email(&config, &link, &username, &to), &config);
+    }
     Ok(HttpResponse::Found()
-        .header("Location", "/edit/index.gmi")
+        .header("Location", "/my_site")
         .finish())
 }
 
@@ -212,55 +536,102 @@ async fn index(
     conn: DbConn,
     config: web::Data<Config>,
 ) -> Result<HttpResponse, FlounderError> {
-    let conn = conn.lock().unwrap(); // TODO
-    let mut stmt = conn.prepare_cached(
-        r#"
-        SELECT user.username from user;
-        "#,
-    )?;
-    let mut usernames = vec![];
-    let mut users_res = stmt.query(NO_PARAMS)?;
-    while let Some(row) = users_res.next()? {
-        usernames.push(row.get(0)?);
-    }
+    let csrf_token = session_csrf_token(&id, &conn)?;
+    let conn = conn.get()?;
+    let mut usernames = db::usernames(&conn)?;
     let mut rng = rand::thread_rng();
     usernames.shuffle(&mut rng);
 
-    let mut stmt = conn.prepare_cached(
-        r#"
-        SELECT user.username, file.user_path, file.updated_at 
-        FROM file 
-        JOIN user
-        ON file.user_id = user.id
-        ORDER BY file.updated_at DESC
-        LIMIT 64"#,
-    )?;
-    let files_res = stmt.query_map(NO_PARAMS, |row| {
-        Ok(RenderedFile {
-            username: row.get(0)?,
-            user_path: row.get(1)?,
-            time_ago: rendered_time_ago(row.get(2)?),
+    let files = db::recent_files(&conn, 64)?
+        .into_iter()
+        .map(|f| RenderedFile {
+            username: f.username,
+            user_path: f.user_path,
+            time_ago: rendered_time_ago(f.updated_at),
         })
-    })?;
+        .collect();
     let template = IndexTemplate {
-        logged_in: id.identity().is_some(),
+        logged_in: csrf_token.is_some(),
+        csrf_token: &csrf_token.unwrap_or_default(),
         server_name: &config.server_name,
-        files: files_res.map(|a| a.unwrap()).collect(),
+        files: files,
         users: usernames,
     };
     template.into_response()
 }
 
-async fn register_page(config: web::Data<Config>) -> Result<HttpResponse, FlounderError> {
+fn render_register(
+    config: &Config,
+    csrf_token: &str,
+    invite_code: &str,
+    errors: Vec<&str>,
+) -> Result<HttpResponse, FlounderError> {
     RegisterTemplate {
-        errors: vec![],
+        errors: errors,
         server_name: &config.server_name,
+        csrf_token: csrf_token,
+        invite_code: invite_code,
+        invite_field: config.registration != Registration::Open,
+        invite_required: config.registration == Registration::Invite,
     }
     .into_response()
 }
 
-async fn login_page() -> Result<HttpResponse, FlounderError> {
-    LoginTemplate { errors: vec![] }.into_response()
+#[derive(Deserialize)]
+struct RegisterQuery {
+    invite: Option<String>, // from a shared invite link
+}
+
+async fn register_page(
+    r: HttpRequest,
+    query: web::Query<RegisterQuery>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, FlounderError> {
+    let csrf_token = csrf::anonymous_token(&r);
+    let invite_code = query.invite.as_deref().unwrap_or("");
+    let response = render_register(&config, &csrf_token, invite_code, vec![])?;
+    Ok(csrf::with_cookie(response, &csrf_token, config.tls_enabled))
+}
+
+async fn login_page(
+    r: HttpRequest,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, FlounderError> {
+    let csrf_token = csrf::anonymous_token(&r);
+    let response = LoginTemplate {
+        errors: vec![],
+        csrf_token: &csrf_token,
+    }
+    .into_response()?;
+    Ok(csrf::with_cookie(response, &csrf_token, config.tls_enabled))
+}
+
+/// The manage page, along with any errors from the last edit or upload
+fn render_my_site(
+    conn: &Connection,
+    user_id: &str,
+    username: &str,
+    config: &Config,
+    csrf_token: &str,
+    errors: Vec<String>,
+) -> Result<HttpResponse, FlounderError> {
+    let paths = db::user_paths(conn, user_id)?;
+    let (used, quota) = db::user_usage(conn, user_id, config)?;
+    let verified = verification::status(conn, user_id)?.map_or(false, |(_, verified)| verified);
+    MySiteTemplate {
+        invites_enabled: config.registration != Registration::Open,
+        unverified: config.verify_email && !verified,
+        can_publish: verification::can_publish(conn, user_id, config)?,
+        logged_in: true,
+        csrf_token: csrf_token,
+        username: username,
+        errors: errors,
+        server_name: &config.server_name,
+        files: file_tree(&paths),
+        usage: rendered_size(used),
+        quota: rendered_size(quota),
+    }
+    .into_response()
 }
 
 async fn my_site(
@@ -269,72 +640,120 @@ async fn my_site(
     config: web::Data<Config>,
 ) -> Result<HttpResponse, FlounderError> {
     // replace impl with specific
-    if let Some(idstr) = id.identity() {
-        let (user_id, username) = parse_identity(idstr);
-        let conn = conn
-            .lock()
-            .map_err(|_| actix_error::ErrorInternalServerError("Internal Server Error"))?;
-        let mut stmt = conn
-            .prepare_cached(
-                r#"
-            SELECT file.user_path, file.updated_at
-            FROM file where user_id = (?)
-            ORDER BY user_path;
-            "#,
-            )
-            .unwrap();
-        let res = stmt
-            .query_map(&[user_id], |row| {
-                Ok(RenderedFile {
-                    username: username.clone(), // TODO remove clone
-                    user_path: row.get(0)?,
-                    time_ago: rendered_time_ago(row.get(1)?),
-                })
-            })
-            .map_err(actix_error::ErrorInternalServerError)?;
-        MySiteTemplate {
-            logged_in: true,
-            username: &username,
-            errors: vec![],
-            server_name: &config.server_name,
-            files: res.map(|a| a.unwrap()).collect(),
+    match session_user(&id, &conn) {
+        Ok((user_id, username)) => {
+            let csrf_token = session_csrf_token(&id, &conn)?.unwrap_or_default();
+            let conn = conn.get()?;
+            render_my_site(&conn, &user_id, &username, &config, &csrf_token, vec![])
         }
-        .into_response()
-    } else {
         // flash you must be logged in?
-        Ok(HttpResponse::Found().header("Location", "/login").finish()) // TODO
+        Err(FlounderError::Unauthorized) => {
+            Ok(HttpResponse::Found().header("Location", "/login").finish()) // TODO
+        }
+        // a database that's down isn't the same as being logged out
+        Err(e) => Err(e),
     }
 }
 
+fn render_invites(
+    conn: &Connection,
+    user_id: &str,
+    config: &Config,
+    csrf_token: &str,
+    errors: Vec<String>,
+) -> Result<HttpResponse, FlounderError> {
+    InvitesTemplate {
+        logged_in: true,
+        csrf_token: csrf_token,
+        errors: errors,
+        invites: invite::list(conn, user_id)?,
+        register_url: &format!("{}/register?invite=", config.base_url()),
+    }
+    .into_response()
+}
+
+async fn invites_page(
+    id: Identity,
+    conn: DbConn,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, FlounderError> {
+    let (user_id, _) = session_user(&id, &conn)?;
+    let csrf_token = session_csrf_token(&id, &conn)?.unwrap_or_default();
+    render_invites(&*conn.get()?, &user_id, &config, &csrf_token, vec![])
+}
+
+async fn create_invite(
+    id: Identity,
+    conn: DbConn,
+    form: web::Form<CsrfForm>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, FlounderError> {
+    let (user_id, _) = session_user(&id, &conn)?;
+    check_csrf(&id, &conn, &form.csrf_token)?;
+    let conn = conn.get()?;
+    if invite::outstanding(&conn, &user_id)? >= config.invites_per_user {
+        let error = format!(
+            "You can have {} unused invites at a time. Revoke one or wait for it to be used.",
+            config.invites_per_user
+        );
+        return render_invites(&conn, &user_id, &config, &form.csrf_token, vec![error]);
+    }
+    let user_id: i64 = user_id
+        .parse()
+        .map_err(|_| FlounderError::Internal("user id is not a number"))?;
+    invite::create(&conn, Some(user_id))?;
+    Ok(HttpResponse::Found()
+        .header("Location", "/invites")
+        .finish())
+}
+
+async fn revoke_invite(
+    id: Identity,
+    conn: DbConn,
+    code: web::Path<String>,
+    form: web::Form<CsrfForm>,
+) -> Result<HttpResponse, FlounderError> {
+    let (user_id, _) = session_user(&id, &conn)?;
+    check_csrf(&id, &conn, &form.csrf_token)?;
+    invite::revoke(&*conn.get()?, &user_id, &code)?;
+    Ok(HttpResponse::Found()
+        .header("Location", "/invites")
+        .finish())
+}
+
 #[derive(Deserialize)]
 struct EditFileForm {
     file_text: String,
+    csrf_token: String,
 }
 
 async fn edit_file_page(
     id: Identity,
+    conn: DbConn,
     local_path: web::Path<String>,
     config: web::Data<Config>,
 ) -> Result<HttpResponse, FlounderError> {
     // read file to string
-    let identity = id
-        .identity()
-        .ok_or(error::FlounderError::UnauthorizedError)?;
-    let (_, username) = parse_identity(identity);
-    let filename = sanitize_filename::sanitize(local_path.as_str());
+    let (_, username) = session_user(&id, &conn)?;
+    let csrf_token = session_csrf_token(&id, &conn)?.unwrap_or_default();
+    let filename = match normalize_user_path(local_path.as_str()) {
+        Some(filename) => filename,
+        None => return Err(FlounderError::validation("Invalid file path")),
+    };
     let full_path = Path::new(&config.file_directory)
         .join(&username)
-        .join(&filename); // TODO sanitize
+        .join(&filename);
     let file_text = std::fs::read_to_string(full_path).unwrap_or("".to_string());
     let template = EditFileTemplate {
         filename: &filename,
         server_name: &config.server_name,
         file_text: &file_text,
+        csrf_token: &csrf_token,
     };
     return template.into_response();
 }
 
-// return error strs
+/// Validation and QuotaExceeded errors are for showing on the manage page
 // this function is weird because i'm bad at rust
 fn upsert_file(
     data: &[u8],
@@ -342,48 +761,61 @@ fn upsert_file(
     username: &str,
     user_id: &str,
     local_path: &str,
-    file_directory: &str,
-) -> Result<Vec<String>, FlounderError> {
+    config: &Config,
+) -> Result<(), FlounderError> {
     let mut errors = vec![];
-    let conn = conn.lock().unwrap();
-    let mut stmt = conn.prepare_cached(
-        r#"
-        SELECT COUNT(*) FROM file
-        where user_id = (?1)
-        "#,
-    )?;
-    let count: u32 = stmt.query_row(&[user_id], |r| r.get(0))?;
-    if count >= 128 {
-        return Ok(vec!["You have the max number of files. Delete some to make room for more.".to_owned()]);
-    }
-    let filename = &sanitize_filename::sanitize(local_path);
+    let conn = conn.get()?;
+    if !verification::can_publish(&conn, user_id, config)? {
+        return Err(FlounderError::validation(
+            "Verify your email address before publishing. Check your inbox for the link.",
+        ));
+    }
+    let filename = &match normalize_user_path(local_path) {
+        Some(filename) => filename,
+        None => return Err(FlounderError::validation("Invalid file path.")),
+    };
     // validate
     if !ok_extension(filename) {
         errors.push("Invalid file extension.".to_owned());
     }
-    let full_path = Path::new(&file_directory).join(&username).join(filename);
-    std::fs::create_dir_all(full_path.parent().unwrap()).ok();
+    if filename == feed::GEMFEED_PATH {
+        errors.push("gemfeed.gmi is generated for you from your other pages.".to_owned());
+    }
+    let full_path = Path::new(&config.file_directory)
+        .join(&username)
+        .join(filename);
+    let full_path_str = full_path.to_str().unwrap();
+    // overwriting a file doesn't count as a new file
+    let other_files = db::other_files_count(&conn, user_id, full_path_str)?;
+    if other_files >= config.max_files_per_user {
+        errors.push(
+            "You have the max number of files. Delete some to make room for more.".to_owned(),
+        );
+    }
     if errors.len() > 0 {
-        return Ok(errors);
-    }
-    let mut stmt = conn.prepare_cached(
-        r#"
-    INSERT INTO file (user_path, user_id, full_path)
-    VALUES (?1, ?2, ?3)
-    ON CONFLICT(full_path) DO UPDATE SET
-    updated_at=strftime('%s', 'now')
-    "#,
-    )?;
-    // TODO -- limit max files per user to configurable X (default 128)
+        return Err(FlounderError::Validation(errors));
+    }
+    let (used, quota) = db::user_usage(&conn, user_id, config)?;
+    // what save_revision leaves behind: with history on the old version stays as a revision,
+    // and the oldest ones past max_revisions go
+    let freed = match config.max_revisions {
+        0 => db::file_size(&conn, full_path_str)?.unwrap_or(0),
+        max_revisions => db::pruned_revisions_size(&conn, full_path_str, max_revisions)?,
+    };
+    if used - freed + data.len() as i64 > quota {
+        return Err(FlounderError::QuotaExceeded { quota: quota });
+    }
+    std::fs::create_dir_all(full_path.parent().unwrap())?;
+    db::save_revision(&conn, full_path_str, config.max_revisions)?;
     let mut file = std::fs::OpenOptions::new()
         .read(true)
         .write(true)
         .create(true)
         .truncate(true)
         .open(&full_path)?;
-    file.write(data)?;
-    stmt.execute(&[filename, user_id, full_path.to_str().unwrap()])?;
-    Ok(vec![])
+    file.write_all(data)?;
+    db::upsert_file(&conn, filename, user_id, full_path_str, data.len())?;
+    Ok(())
 }
 
 async fn edit_file(
@@ -393,23 +825,28 @@ async fn edit_file(
     conn: DbConn,
     config: web::Data<Config>,
 ) -> Result<HttpResponse, FlounderError> {
-    let identity = id
-        .identity()
-        .ok_or(error::FlounderError::UnauthorizedError)?;
-    let file_directory: String = config.file_directory.clone();
-    let (user_id, username) = parse_identity(identity);
-    let errors = upsert_file(
+    let (user_id, username) = session_user(&id, &conn)?;
+    check_csrf(&id, &conn, &form.csrf_token)?;
+    let saved = upsert_file(
         form.file_text.as_bytes(),
         &conn,
         &username,
         &user_id,
         local_path.as_str(),
-        &file_directory,
-    )?;
-    if errors.len() > 0 {
-        // temporary
-        return Ok(HttpResponse::InternalServerError().body(format!("{:?}", errors)));
+        &config,
+    );
+    if let Some(errors) = saved.as_ref().err().and_then(|e| e.form_errors()) {
+        let conn = conn.get()?;
+        return render_my_site(
+            &conn,
+            &user_id,
+            &username,
+            &config,
+            &form.csrf_token,
+            errors,
+        );
     }
+    saved?;
     Ok(HttpResponse::Found()
         .header("Location", "/my_site")
         .finish()) // TODO g
@@ -423,31 +860,41 @@ async fn upload_file(
     conn: DbConn,
     config: web::Data<Config>,
 ) -> Result<HttpResponse, FlounderError> {
-    let identity = id
-        .identity()
-        .ok_or(error::FlounderError::UnauthorizedError)?;
-    let (user_id, username) = parse_identity(identity); // fail otheriwse
-    let file_directory: String = config.file_directory.clone();
-    while let Ok(Some(mut field)) = payload.try_next().await {
-        let content_type = field.content_disposition().unwrap();
-        let filename = content_type.get_filename().unwrap();
+    let (user_id, username) = session_user(&id, &conn)?;
+    let mut csrf_token = None;
+    while let Some(mut field) = payload.try_next().await? {
+        let content_type = field
+            .content_disposition()
+            .ok_or_else(|| FlounderError::validation("Malformed upload."))?;
+        // the token field comes first in the form, so it's checked before any file is written
+        if content_type.get_name() == Some("csrf_token") {
+            let mut token = vec![];
+            while let Some(chunk) = field.next().await {
+                token.extend(chunk?);
+                if token.len() > 128 {
+                    return Err(FlounderError::Unauthorized);
+                }
+            }
+            let token = String::from_utf8_lossy(&token).to_string();
+            check_csrf(&id, &conn, &token)?;
+            csrf_token = Some(token);
+            continue;
+        }
+        let csrf_token = csrf_token.as_deref().ok_or(FlounderError::Unauthorized)?;
+        let filename = content_type
+            .get_filename()
+            .ok_or_else(|| FlounderError::validation("Choose a file to upload."))?;
         let mut all_data = vec![];
         while let Some(chunk) = field.next().await {
             let data = chunk?;
             all_data.extend(data);
         }
-        let errors = upsert_file(
-            &all_data,
-            &conn,
-            &username,
-            &user_id,
-            filename,
-            &file_directory,
-        )?;
-        if errors.len() > 0 {
-            // temporary
-            return Ok(HttpResponse::InternalServerError().body(format!("{:?}", errors)));
+        let saved = upsert_file(&all_data, &conn, &username, &user_id, filename, &config);
+        if let Some(errors) = saved.as_ref().err().and_then(|e| e.form_errors()) {
+            let conn = conn.get()?;
+            return render_my_site(&conn, &user_id, &username, &config, csrf_token, errors);
         }
+        saved?;
 
         // TODO error handling
     }
@@ -460,25 +907,29 @@ async fn delete_file(
     conn: DbConn,
     id: Identity,
     path: web::Path<String>,
+    form: web::Form<CsrfForm>,
     config: web::Data<Config>,
 ) -> Result<HttpResponse, FlounderError> {
-    let identity = id
-        .identity()
-        .ok_or(error::FlounderError::UnauthorizedError)?;
-    let (_, username) = parse_identity(identity); // fail otheriwse
-    let conn = conn.lock().unwrap();
-    let filename = &sanitize_filename::sanitize(path.as_str());
-    let full_path = Path::new(&config.file_directory)
-        .join(&username)
-        .join(filename); // TODO sanitize
+    let (_, username) = session_user(&id, &conn)?;
+    check_csrf(&id, &conn, &form.csrf_token)?;
+    let conn = conn.get()?;
+    let filename = match normalize_user_path(path.as_str()) {
+        Some(filename) => filename,
+        None => return Err(FlounderError::validation("Invalid file path")),
+    };
+    let user_directory = Path::new(&config.file_directory).join(&username);
+    let full_path = user_directory.join(&filename);
     std::fs::remove_file(&full_path).ok();
+    // clean up directories the delete left empty. remove_dir refuses non-empty ones
+    let mut dir = full_path.parent();
+    while let Some(d) = dir {
+        if d == user_directory || std::fs::remove_dir(d).is_err() {
+            break;
+        }
+        dir = d.parent();
+    }
 
-    let mut stmt = conn.prepare_cached(
-        r#"
-    DELETE FROM file where file.full_path = (?)
-    "#,
-    )?;
-    stmt.execute(&[&full_path.to_str()])?;
+    db::delete_file(&conn, full_path.to_str().unwrap())?;
     // verify idetntiy
     // remove file from dir
     // delete from db
@@ -487,61 +938,374 @@ async fn delete_file(
         .finish()) // TODO g
 }
 
+async fn revisions_page(
+    id: Identity,
+    conn: DbConn,
+    local_path: web::Path<String>,
+) -> Result<HttpResponse, FlounderError> {
+    let (user_id, _) = session_user(&id, &conn)?;
+    let csrf_token = session_csrf_token(&id, &conn)?.unwrap_or_default();
+    let filename = match normalize_user_path(local_path.as_str()) {
+        Some(filename) => filename,
+        None => return Err(FlounderError::validation("Invalid file path")),
+    };
+    let revisions = db::revisions(&*conn.get()?, &user_id, &filename)?
+        .into_iter()
+        .map(|r| RenderedRevision {
+            id: r.id,
+            size: rendered_size(r.size),
+            time_ago: rendered_time_ago(r.created_at),
+        })
+        .collect();
+    RevisionsTemplate {
+        logged_in: true,
+        csrf_token: &csrf_token,
+        filename: &filename,
+        revisions: revisions,
+    }
+    .into_response()
+}
+
+#[derive(Deserialize)]
+struct DiffQuery {
+    old: u32,
+    new: Option<String>, // the form sends an empty string for the current version
+}
+
+async fn diff_page(
+    id: Identity,
+    conn: DbConn,
+    local_path: web::Path<String>,
+    query: web::Query<DiffQuery>,
+) -> Result<HttpResponse, FlounderError> {
+    let (user_id, _) = session_user(&id, &conn)?;
+    let csrf_token = session_csrf_token(&id, &conn)?.unwrap_or_default();
+    let filename = match normalize_user_path(local_path.as_str()) {
+        Some(filename) => filename,
+        None => return Err(FlounderError::validation("Invalid file path")),
+    };
+    let conn = conn.get()?;
+    let old = db::revision_content(&conn, &user_id, &filename, Some(query.old))?;
+    let new_id: Option<u32> = query.new.as_ref().and_then(|n| n.parse().ok());
+    let new = db::revision_content(&conn, &user_id, &filename, new_id)?;
+    let (old, new) = match (old, new) {
+        (Some(old), Some(new)) => (old, new),
+        _ => return Err(FlounderError::NotFound),
+    };
+    let old = String::from_utf8_lossy(&old);
+    let new = String::from_utf8_lossy(&new);
+    DiffTemplate {
+        logged_in: true,
+        csrf_token: &csrf_token,
+        filename: &filename,
+        old_id: query.old,
+        new_id: new_id,
+        lines: line_diff(&old, &new),
+    }
+    .into_response()
+}
+
+#[derive(Deserialize)]
+struct RestoreForm {
+    revision: u32,
+    csrf_token: String,
+}
+
+/// Restoring goes through upsert_file like any other save, so it can be undone too
+async fn restore_revision(
+    id: Identity,
+    conn: DbConn,
+    local_path: web::Path<String>,
+    form: web::Form<RestoreForm>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, FlounderError> {
+    let (user_id, username) = session_user(&id, &conn)?;
+    check_csrf(&id, &conn, &form.csrf_token)?;
+    let filename = match normalize_user_path(local_path.as_str()) {
+        Some(filename) => filename,
+        None => return Err(FlounderError::validation("Invalid file path")),
+    };
+    let content = {
+        let conn = conn.get()?;
+        db::revision_content(&conn, &user_id, &filename, Some(form.revision))?
+    };
+    let content = match content {
+        Some(content) => content,
+        None => return Err(FlounderError::NotFound),
+    };
+    let saved = upsert_file(&content, &conn, &username, &user_id, &filename, &config);
+    if let Some(errors) = saved.as_ref().err().and_then(|e| e.form_errors()) {
+        let conn = conn.get()?;
+        return render_my_site(
+            &conn,
+            &user_id,
+            &username,
+            &config,
+            &form.csrf_token,
+            errors,
+        );
+    }
+    saved?;
+    Ok(HttpResponse::Found()
+        .header("Location", "/my_site")
+        .finish())
+}
+
+async fn updates_atom_feed(
+    conn: DbConn,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, FlounderError> {
+    let conn = conn.get()?;
+    let entries = feed::recent_entries(&conn, feed::UPDATES_LIMIT)?;
+    let site_url = format!("{}/", config.base_url());
+    let body = feed::atom_feed(
+        &format!("Recently updated on {}", config.server_name),
+        &format!("{}{}", site_url, feed::UPDATES_ATOM_PATH),
+        &site_url,
+        &entries,
+        |e| format!("{}{}", config.capsule_url(&e.username), e.user_path),
+    );
+    Ok(HttpResponse::Ok()
+        .content_type("application/atom+xml")
+        .body(body))
+}
+
+async fn user_atom_feed(
+    username: web::Path<String>,
+    conn: DbConn,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, FlounderError> {
+    let conn = conn.get()?;
+    if !db::user_exists(&conn, &username)? {
+        return Err(FlounderError::NotFound);
+    }
+    let entries = feed::user_entries(&conn, &username)?;
+    let site_url = config.capsule_url(&username);
+    let body = feed::atom_feed(
+        &username,
+        &format!("{}{}", site_url, feed::ATOM_PATH),
+        &site_url,
+        &entries,
+        |e| format!("{}{}", site_url, e.user_path),
+    );
+    Ok(HttpResponse::Ok()
+        .content_type("application/atom+xml")
+        .body(body))
+}
+
+async fn user_gemfeed(
+    username: web::Path<String>,
+    r: HttpRequest,
+    conn: DbConn,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, FlounderError> {
+    let conn = conn.get()?;
+    if !db::user_exists(&conn, &username)? {
+        return Err(FlounderError::NotFound);
+    }
+    let entries = feed::user_entries(&conn, &username)?;
+    // relative links work from both the capsule root and /user/{username}/
+    let gmi = feed::gemfeed(&username, &entries, |e| e.user_path.clone());
+    if r.query_string() == "raw=1" {
+        return Ok(HttpResponse::from(gmi));
+    }
+    let gmi = proxy::proxy_links(&gmi, None, &config.proxy_url());
+    let string = gmi2html::GeminiConverter::new(&gmi).to_html();
+    GmiPageTemplate {
+        title: &username,
+        html_block: &string,
+    }
+    .into_response()
+}
+
 // redundant -- cleanup
 async fn serve_home(
     user: web::Path<String>,
+    r: HttpRequest,
+    conn: DbConn,
     config: web::Data<Config>,
-) -> Result<HttpResponse, Error> {
-    let full_path = Path::new(&config.file_directory)
-        .join(user.as_str())
-        .join("index.gmi");
-    let gmi_file = std::fs::read_to_string(full_path).unwrap();
-    let string = gmi2html::GeminiConverter::new(&gmi_file)
-        .proxy_url(&config.proxy_url)
-        .inline_images(true)
-        .to_html();
-    let template = GmiPageTemplate {
-        title: user.as_str(),
-        html_block: &string,
-    };
-    return Ok(template.into_response().unwrap());
+) -> Result<HttpResponse, FlounderError> {
+    serve_user_file(&r, &*conn.get()?, &config, &user, "index.gmi", &user)
 }
+
 /// Rather than route through the gmi server, we write an
 /// HTTP client that behaves like the gmi proxy, for performance
 /// replace some w/ nginx?
 async fn serve_user_content(
     path: web::Path<(String, String)>,
     r: HttpRequest,
+    conn: DbConn,
     config: web::Data<Config>,
-) -> Result<HttpResponse, Error> {
+) -> Result<HttpResponse, FlounderError> {
     let username = &path.0;
-    let filename = &sanitize_filename::sanitize(&path.1); // probably not necc but eh/
-    let full_path = Path::new(&config.file_directory)
-        .join(&username)
-        .join(filename);
+    let filename = match normalize_request_path(&path.1) {
+        Some(filename) if normalize_user_path(username).as_ref() == Some(username) => filename,
+        _ => return Err(FlounderError::NotFound),
+    };
     // empty path render index
-    if full_path.extension() == Some(OsStr::new("gmi"))
-        || full_path.extension() == Some(OsStr::new("gemini"))
-    {
-        let gmi_file = std::fs::read_to_string(full_path).unwrap();
+    serve_user_file(&r, &*conn.get()?, &config, username, &filename, &filename)
+}
+
+/// Only what's in the file table gets served, so unknown users and files are a 404
+fn serve_user_file(
+    r: &HttpRequest,
+    conn: &Connection,
+    config: &Config,
+    username: &str,
+    user_path: &str,
+    title: &str,
+) -> Result<HttpResponse, FlounderError> {
+    let full_path = match db::full_path(conn, username, user_path)? {
+        Some(full_path) => full_path,
+        None => return not_found_page(conn, config, username),
+    };
+    let extension = Path::new(user_path).extension();
+    if extension == Some(OsStr::new("gmi")) || extension == Some(OsStr::new("gemini")) {
+        let gmi_file = match std::fs::read_to_string(&full_path) {
+            Ok(gmi_file) => gmi_file,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return not_found_page(conn, config, username)
+            }
+            Err(e) => return Err(e.into()),
+        };
         if r.query_string() == "raw=1" {
             return Ok(HttpResponse::from(gmi_file));
         }
-        let string = gmi2html::GeminiConverter::new(&gmi_file)
-            .proxy_url(&config.proxy_url)
-            .inline_images(true)
-            .to_html();
-        let template = GmiPageTemplate {
-            title: filename,
-            html_block: &string,
-        };
-        return Ok(template.into_response().unwrap());
+        return render_gmi(&gmi_file, title, config);
+    }
+    match fs::NamedFile::open(&full_path) {
+        Ok(file) => file
+            .into_response(r)
+            .map_err(|_| FlounderError::Internal("could not serve a user file")),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+            not_found_page(conn, config, username)
+        }
+        Err(e) => Err(e.into()),
     }
-    fs::NamedFile::open(full_path).unwrap().into_response(&r) // todo error
 }
 
-async fn proxy(url: web::Path<String>) {
-    client::get_gmi_data(&url);
+fn render_gmi(gmi: &str, title: &str, config: &Config) -> Result<HttpResponse, FlounderError> {
+    let gmi = proxy::proxy_links(gmi, None, &config.proxy_url());
+    let string = gmi2html::GeminiConverter::new(&gmi)
+        .inline_images(true)
+        .to_html();
+    GmiPageTemplate {
+        title: title,
+        html_block: &string,
+    }
+    .into_response()
+}
+
+/// The capsule's own 404.gmi if it has one, otherwise the usual error page.
+/// HTTP only: a Gemini 51 response has no body to put it in
+fn not_found_page(
+    conn: &Connection,
+    config: &Config,
+    username: &str,
+) -> Result<HttpResponse, FlounderError> {
+    let custom = match db::full_path(conn, username, feed::NOT_FOUND_PAGE)? {
+        Some(full_path) => std::fs::read_to_string(full_path).ok(),
+        None => None,
+    };
+    match custom {
+        Some(gmi) => {
+            let mut response = render_gmi(&gmi, username, config)?;
+            *response.status_mut() = StatusCode::NOT_FOUND;
+            Ok(response)
+        }
+        None => Err(FlounderError::NotFound),
+    }
+}
+
+fn proxy_error(status: StatusCode, error: String) -> Result<HttpResponse, FlounderError> {
+    Ok(error::error_page(status, &error))
+}
+
+/// Fetches gemini://{url} and renders it for the browser. Not a full Gemini client --
+/// no client certificates
+async fn proxy_page(
+    r: HttpRequest,
+    conn: DbConn,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, FlounderError> {
+    // the raw path, so percent-encoding reaches the gemini server untouched
+    let proxy_path = r.uri().path().trim_start_matches("/proxy/");
+    // the input form below submits ?input=..., anything else is passed on as is
+    let input = url::form_urlencoded::parse(r.query_string().as_bytes())
+        .find(|(key, _)| key == "input")
+        .map(|(_, value)| utf8_percent_encode(&value, NON_ALPHANUMERIC).to_string());
+    let query = input.as_deref().unwrap_or_else(|| r.query_string());
+    let gemini_url = match proxy::gemini_url(proxy_path, query) {
+        Some(url) => url,
+        None => return proxy_error(StatusCode::BAD_REQUEST, "Not a Gemini URL".to_string()),
+    };
+    let cached = proxy::cached_response(&*conn.get()?, gemini_url.as_str(), &config)?;
+    let response = match cached {
+        Some(response) => response,
+        None => match client::get_follow_redirect(gemini_url.as_str(), &conn, &config.proxy_ports)
+            .await
+        {
+            Ok(response) => {
+                // don't keep what people type into input prompts around
+                if input.is_none() {
+                    let conn = conn.get()?;
+                    proxy::cache_response(&conn, gemini_url.as_str(), &response, &config)?;
+                }
+                response
+            }
+            Err(e) => return proxy_error(StatusCode::BAD_GATEWAY, e.to_string()),
+        },
+    };
+    match response.status / 10 {
+        1 => ProxyInputTemplate {
+            prompt: &response.meta,
+            sensitive: response.status == 11,
+        }
+        .into_response(),
+        2 => match response.mime_type() {
+            Some("text/gemini") => {
+                let gmi = String::from_utf8_lossy(&response.body);
+                let gmi = proxy::proxy_links(&gmi, Some(&response.url), &config.proxy_url());
+                let title = feed::gmi_title(&gmi).unwrap_or(response.url.as_str());
+                let html = gmi2html::GeminiConverter::new(&gmi).to_html();
+                GmiPageTemplate {
+                    title: title,
+                    html_block: &html,
+                }
+                .into_response()
+            }
+            // served from our own origin, so keep scripts in anything else from running
+            _ => Ok(HttpResponse::Ok()
+                .content_type(response.meta.as_str())
+                .header("Content-Security-Policy", "sandbox")
+                .body(response.body)),
+        },
+        // only redirects off gemini get this far. The user decides whether to follow them
+        3 => {
+            let location = response.url.join(&response.meta).ok();
+            ProxyRedirectTemplate {
+                url: response.url.as_str(),
+                location: location.as_ref().map_or(&response.meta, |l| l.as_str()),
+                linkable: location
+                    .as_ref()
+                    .map_or(false, |l| ["http", "https"].contains(&l.scheme())),
+            }
+            .into_response()
+        }
+        _ => {
+            let status = match response.status {
+                51 => StatusCode::NOT_FOUND,
+                _ => StatusCode::BAD_GATEWAY,
+            };
+            proxy_error(
+                status,
+                format!(
+                    "{} responded {} {}",
+                    response.url, response.status, response.meta
+                ),
+            )
+        }
+    }
 }
 
 async fn show_statuses(
@@ -549,22 +1313,19 @@ async fn show_statuses(
     conn: DbConn,
     config: web::Data<Config>,
 ) -> Result<HttpResponse, FlounderError> {
-    let conn = conn.lock().unwrap();
-    let mut stmt = conn.prepare_cached(
-        r#"
-    SELECT full_path, username FROM file
-    JOIN user 
-    ON file.user_id = user.id
-    WHERE user_path = 'twtxt.txt'"#,
-    )?;
+    let csrf_token = session_csrf_token(&id, &conn)?;
+    let twtxt_files = db::twtxt_files(&*conn.get()?)?;
 
     let mut statuses: Vec<TwtxtStatus> = vec![];
-    let mut res = stmt.query(NO_PARAMS)?;
-    while let Some(row) = res.next()? {
-        let full_path: String = row.get(0).unwrap();
-        let status_data = std::fs::read_to_string(full_path).unwrap();
+    for (full_path, username) in twtxt_files {
+        // a file removed from disk but still in the table has nothing to show
+        let status_data = match std::fs::read_to_string(full_path) {
+            Ok(status_data) => status_data,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
         for line in status_data.lines() {
-            let new_status = TwtxtStatus::new(row.get(1).unwrap(), line.to_string());
+            let new_status = TwtxtStatus::new(username.clone(), line.to_string());
             if new_status.is_some() {
                 statuses.push(new_status.unwrap());
             }
@@ -575,41 +1336,89 @@ async fn show_statuses(
     // get all statuses push to vec
     // sort statuses by date
     let template = StatusesTemplate {
-        logged_in: id.identity().is_some(),
+        logged_in: csrf_token.is_some(),
+        csrf_token: &csrf_token.unwrap_or_default(),
         statuses: statuses,
         server_name: &config.server_name,
     };
     template.into_response()
 }
+/// Does what nginx.conf does: {user}.{server_name}/{path} -> /user/{user}/{path}
+/// Static files are shared, so those are left alone
+fn rewrite_capsule_host(req: &mut ServiceRequest, server_host: &str) {
+    let host = req.connection_info().host().to_lowercase();
+    let host = host.split(':').next().unwrap_or("");
+    let username = match capsule_for_host(host, server_host) {
+        Some(Some(username)) => username.to_string(),
+        _ => return,
+    };
+    if req.path().starts_with("/static/") {
+        return;
+    }
+    let mut path = format!("/user/{}{}", username, req.path());
+    if let Some(query) = req.uri().query() {
+        path.push('?');
+        path.push_str(query);
+    }
+    if let Ok(uri) = path.parse::<Uri>() {
+        req.match_info_mut().get_mut().update(&uri);
+        req.head_mut().uri = uri;
+    }
+}
+
 // https://actix.rs/docs/extractors/
 // run gemini server in separate thread
 #[actix_rt::main]
-pub async fn run_server(config_path: String) -> std::io::Result<()> {
+pub async fn run_server(config: Config) -> std::io::Result<()> {
     // Error type?
-    // db::initialize_tables().unwrap();
     env_logger::from_env(Env::default().default_filter_or("info")).init();
-    // parse arguments using light library
-    // initialize config
-    HttpServer::new(move || {
-        let config_str = std::fs::read_to_string(&config_path).unwrap();
-        let config: Config = toml::from_str(&config_str).unwrap();
+    let pool = db::pool(&config).map_err(to_io_error)?;
+    db::migrate(&mut *pool.get().map_err(to_io_error)?).map_err(to_io_error)?;
+    // validated in Config::load
+    let tls_paths = match (&config.tls_cert_path, &config.tls_key_path) {
+        (Some(cert), Some(key)) if config.tls_enabled => Some((cert.clone(), key.clone())),
+        _ => None,
+    };
+    let http_bind = config.http_bind.clone();
+    let unix_socket = config.unix_socket.clone();
+    let workers = config.workers;
+    let session_key = session::signing_key(&config)?;
+    let gemini_config = config.clone();
+    let gemini_pool = pool.clone();
+    std::thread::spawn(move || {
+        if let Err(e) = gemini::run_server(gemini_config, gemini_pool) {
+            log::error!("Gemini server stopped: {}", e);
+        }
+    });
+    let mut server = HttpServer::new(move || {
+        let config = config.clone();
+        let session_key = session_key.clone();
         let store = MemoryStore::new(); // used for ratelimit
-        let conn = Mutex::new(Connection::open(&config.db_path).unwrap()); // TODO config, error?
+        let server_host = config.server_host();
+        let serve_all_content = config.serve_all_content;
         App::new()
             .wrap(Logger::default())
             .wrap(NormalizePath) // does this do anything
             .wrap(IdentityService::new(
-                CookieIdentityPolicy::new(&[0; 32])
+                CookieIdentityPolicy::new(&session_key)
                     // domain?
                     // https://docs.rs/actix-identity/0.3.0-alpha.1/actix_identity/struct.CookieIdentityPolicy.html
                     .name("auth-cookie")
-                    .secure(false),
+                    .max_age(config.session_lifetime_secs() as i64)
+                    .secure(config.tls_enabled),
             ))
-            .data(conn)
+            .wrap_fn(move |mut req, srv| {
+                if serve_all_content {
+                    rewrite_capsule_host(&mut req, &server_host);
+                }
+                srv.call(req)
+            })
+            .data(pool.clone())
             .app_data(web::Form::<EditFileForm>::configure(|cfg| {
                 cfg.limit(32 * 1024)
             }))
             .service(fs::Files::new("/static", &config.static_path).show_files_listing()) // TODO configurable
+            .data(verification::LinkSigner::new(&session_key))
             .data(config)
             .route("/", web::get().to(index))
             // TODO -- setup to use nginx in production
@@ -624,7 +1433,41 @@ pub async fn run_server(config_path: String) -> std::io::Result<()> {
                             .with_max_requests(20),
                     ), //   DO consolidate
             )
-            .route("/logout", web::get().to(logout)) // TODO should be post
+            .route("/logout", web::post().to(logout))
+            .route("/logout_all", web::post().to(logout_all))
+            .route("/invites", web::get().to(invites_page))
+            .route("/invites", web::post().to(create_invite))
+            .route("/invites/{code}/revoke", web::post().to(revoke_invite))
+            .route("/change_password", web::get().to(change_password_page))
+            .route("/change_password", web::post().to(change_password))
+            .service(
+                web::resource("/forgot_password")
+                    .route(web::post().to(forgot_password))
+                    .route(web::get().to(forgot_password_page))
+                    .wrap(
+                        RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+                            .with_interval(Duration::from_secs(3600))
+                            .with_max_requests(10),
+                    ),
+            )
+            .route(
+                "/reset_password/{token}",
+                web::get().to(reset_password_page),
+            )
+            .route("/reset_password/{token}", web::post().to(reset_password))
+            .route(
+                "/verify_email/{user_id}/{expires_at}/{signature}",
+                web::get().to(verify_email),
+            )
+            .service(
+                web::resource("/resend_verification")
+                    .route(web::post().to(resend_verification))
+                    .wrap(
+                        RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+                            .with_interval(Duration::from_secs(3600))
+                            .with_max_requests(5),
+                    ),
+            )
             .service(
                 web::resource("/register")
                     .route(web::post().to(register))
@@ -637,17 +1480,53 @@ pub async fn run_server(config_path: String) -> std::io::Result<()> {
             )
             .route("/register", web::get().to(register_page))
             .route("/statuses", web::get().to(show_statuses))
+            .service(
+                web::resource("/proxy/{url:.*}")
+                    .route(web::get().to(proxy_page))
+                    .wrap(
+                        RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+                            .with_interval(Duration::from_secs(60))
+                            .with_max_requests(60),
+                    ),
+            )
+            .route("/updates.atom", web::get().to(updates_atom_feed))
             .route("/upload", web::post().to(upload_file))
+            .route("/user/{username}/", web::get().to(serve_home))
+            .route("/user/{username}/atom.xml", web::get().to(user_atom_feed))
+            .route("/user/{username}/gemfeed.gmi", web::get().to(user_gemfeed))
             .route(
-                "/user/{username}/{user_file_path}",
+                "/user/{username}/{user_file_path:.*}",
                 web::get().to(serve_user_content),
             )
-            .route("/user/{username}/", web::get().to(serve_home))
-            .route("/edit/{user_file_path}", web::get().to(edit_file_page))
-            .route("/edit/{user_file_path}", web::post().to(edit_file))
-            .route("/delete/{user_file_path}", web::post().to(delete_file))
-    })
-    .bind("127.0.0.1:8088")?
-    .run()
-    .await
+            .route("/edit/{user_file_path:.*}", web::get().to(edit_file_page))
+            .route("/edit/{user_file_path:.*}", web::post().to(edit_file))
+            .route("/delete/{user_file_path:.*}", web::post().to(delete_file))
+            .route(
+                "/revisions/{user_file_path:.*}",
+                web::get().to(revisions_page),
+            )
+            .route("/diff/{user_file_path:.*}", web::get().to(diff_page))
+            .route(
+                "/restore/{user_file_path:.*}",
+                web::post().to(restore_revision),
+            )
+    });
+    if let Some(workers) = workers {
+        server = server.workers(workers);
+    }
+    for addr in &http_bind {
+        server = match &tls_paths {
+            // each listener builds its own acceptor
+            Some((cert, key)) => server.bind_openssl(addr, ssl_acceptor(cert, key)?)?,
+            None => server.bind(addr)?,
+        };
+    }
+    // for running behind a reverse proxy, so never TLS
+    #[cfg(unix)]
+    {
+        if let Some(path) = &unix_socket {
+            server = server.bind_uds(path)?;
+        }
+    }
+    server.run().await
 }
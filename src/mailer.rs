@@ -0,0 +1,170 @@
+/// Outgoing email, for password resets and the like. Either dropped as .eml files into mail_dir,
+/// for testing or for something else to pick up, or handed to an SMTP relay on smtp_relay.
+/// The relay is expected to be local and trusted: no TLS, no auth
+use crate::session::random_hex;
+use crate::Config;
+use chrono::Utc;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::time::Duration;
+
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+pub trait Mailer: Send {
+    fn send(&self, email: &Email) -> io::Result<()>;
+}
+
+/// None when mail isn't configured
+pub fn from_config(config: &Config) -> Option<Box<dyn Mailer>> {
+    let from = config.mail_from();
+    let host = config.server_host();
+    if let Some(relay) = &config.smtp_relay {
+        return Some(Box::new(SmtpMailer {
+            relay: relay.clone(),
+            from: from,
+            host: host,
+        }));
+    }
+    config.mail_dir.as_ref().map(|dir| {
+        Box::new(DirectoryMailer {
+            dir: PathBuf::from(dir),
+            from: from,
+            host: host,
+        }) as Box<dyn Mailer>
+    })
+}
+
+/// Addresses come from user input; a line break in one would let it add headers or commands
+fn header_value(value: &str) -> String {
+    value.replace(|c| c == '\r' || c == '\n', "")
+}
+
+/// RFC 5322 message with CRLF line endings
+fn format_message(email: &Email, from: &str, host: &str) -> String {
+    let mut message = format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMessage-ID: <{}@{}>\r\n\
+         MIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n",
+        from,
+        header_value(&email.to),
+        header_value(&email.subject),
+        Utc::now().to_rfc2822(),
+        random_hex(16),
+        host
+    );
+    for line in email.body.lines() {
+        message.push_str(line);
+        message.push_str("\r\n");
+    }
+    message
+}
+
+pub struct DirectoryMailer {
+    dir: PathBuf,
+    from: String,
+    host: String,
+}
+
+impl Mailer for DirectoryMailer {
+    fn send(&self, email: &Email) -> io::Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        let filename = format!("{}-{}.eml", Utc::now().timestamp(), random_hex(4));
+        std::fs::write(
+            self.dir.join(filename),
+            format_message(email, &self.from, &self.host),
+        )
+    }
+}
+
+pub struct SmtpMailer {
+    relay: String,
+    from: String,
+    host: String,
+}
+
+impl SmtpMailer {
+    /// Reads a possibly multiline reply and fails unless its code is the expected one
+    fn expect(reader: &mut impl BufRead, code: &str) -> io::Result<()> {
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "SMTP relay closed the connection",
+                ));
+            }
+            if !line.starts_with(code) {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("SMTP relay said: {}", line.trim_end()),
+                ));
+            }
+            // "250-" continues, "250 " ends
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, email: &Email) -> io::Result<()> {
+        let mut stream = TcpStream::connect(&self.relay)?;
+        stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+        stream.set_write_timeout(Some(Duration::from_secs(30)))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        Self::expect(&mut reader, "220")?;
+        let commands = [
+            (format!("HELO {}\r\n", self.host), "250"),
+            (format!("MAIL FROM:<{}>\r\n", self.from), "250"),
+            (format!("RCPT TO:<{}>\r\n", header_value(&email.to)), "250"),
+            ("DATA\r\n".to_string(), "354"),
+        ];
+        for (command, code) in commands.iter() {
+            stream.write_all(command.as_bytes())?;
+            Self::expect(&mut reader, code)?;
+        }
+        // a lone dot ends the message, so dots starting a line get doubled
+        let message = format_message(email, &self.from, &self.host);
+        for line in message.lines() {
+            if line.starts_with('.') {
+                stream.write_all(b".")?;
+            }
+            stream.write_all(line.as_bytes())?;
+            stream.write_all(b"\r\n")?;
+        }
+        stream.write_all(b".\r\n")?;
+        Self::expect(&mut reader, "250")?;
+        stream.write_all(b"QUIT\r\n")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_message() {
+        let email = Email {
+            to: "alice@example.org".to_string(),
+            subject: "Hi".to_string(),
+            body: "line one\nline two".to_string(),
+        };
+        let message = format_message(&email, "flounder@flounder.local", "flounder.local");
+        assert!(message.starts_with("From: flounder@flounder.local\r\nTo: alice@example.org\r\n"));
+        assert!(message.ends_with("\r\n\r\nline one\r\nline two\r\n"));
+    }
+
+    #[test]
+    fn test_smtp_reply() {
+        let mut reply = "250-flounder.local\r\n250 OK\r\n".as_bytes();
+        assert!(SmtpMailer::expect(&mut reply, "250").is_ok());
+        let mut reply = "550 No such user\r\n".as_bytes();
+        assert!(SmtpMailer::expect(&mut reply, "250").is_err());
+    }
+}
@@ -1,5 +1,5 @@
 use argh::FromArgs;
-use flounder::run_server;
+use flounder::{admin, db, run_server, Config};
 
 #[derive(FromArgs, PartialEq, Debug)]
 /// A command with positional arguments.
@@ -13,15 +13,169 @@ struct Arguments {
 enum Sub {
     Admin(Admin),
     RunServer(RunServer),
+    CheckConfig(CheckConfig),
+    Migrate(Migrate),
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
-/// First subcommand.
+/// Administer a running instance
 #[argh(subcommand, name = "admin")]
 struct Admin {
+    /// config file path
+    #[argh(option, short = 'c', default = "default_config()")]
+    config: String,
+    #[argh(subcommand)]
+    command: AdminCommand,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+enum AdminCommand {
+    CreateUser(CreateUser),
+    DeleteUser(DeleteUser),
+    ResetPassword(ResetPassword),
+    ListUsers(ListUsers),
+    Lock(Lock),
+    Unlock(Unlock),
+    Rename(Rename),
+    DiskUsage(DiskUsage),
+    SetQuota(SetQuota),
+    PurgeProxyCache(PurgeProxyCache),
+    ResetLink(ResetLink),
+    CreateInvite(CreateInvite),
+    Pending(Pending),
+    Approve(Approve),
+    Reject(Reject),
+    InviteTree(InviteTree),
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Create a user with a generated password
+#[argh(subcommand, name = "create-user")]
+struct CreateUser {
+    #[argh(positional)]
+    username: String,
+    /// their email address
     #[argh(option)]
-    /// not implemented yet
-    x: usize,
+    email: Option<String>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Delete a user along with all their files
+#[argh(subcommand, name = "delete-user")]
+struct DeleteUser {
+    #[argh(positional)]
+    username: String,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Give a user a generated password and log them out everywhere
+#[argh(subcommand, name = "reset-password")]
+struct ResetPassword {
+    #[argh(positional)]
+    username: String,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// List every user
+#[argh(subcommand, name = "list-users")]
+struct ListUsers {}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Keep a user from logging in, and end their sessions
+#[argh(subcommand, name = "lock")]
+struct Lock {
+    #[argh(positional)]
+    username: String,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Let a locked user log in again
+#[argh(subcommand, name = "unlock")]
+struct Unlock {
+    #[argh(positional)]
+    username: String,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Rename a user and move their files
+#[argh(subcommand, name = "rename")]
+struct Rename {
+    #[argh(positional)]
+    username: String,
+    #[argh(positional)]
+    new_username: String,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Show how much space each user takes up
+#[argh(subcommand, name = "disk-usage")]
+struct DiskUsage {}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Set a user's quota in bytes, or "default"
+#[argh(subcommand, name = "set-quota")]
+struct SetQuota {
+    #[argh(positional)]
+    username: String,
+    #[argh(positional)]
+    quota: String,
+}
+
+fn parse_quota(value: &str) -> Result<Option<u64>, admin::AdminError> {
+    if value == "default" {
+        return Ok(None);
+    }
+    value.parse().map(Some).map_err(|_| {
+        admin::AdminError::Invalid(format!("{} is not a number of bytes or \"default\"", value))
+    })
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Drop every page cached by the HTTP proxy
+#[argh(subcommand, name = "purge-proxy-cache")]
+struct PurgeProxyCache {}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Print a one-hour password reset link for a user
+#[argh(subcommand, name = "reset-link")]
+struct ResetLink {
+    #[argh(positional)]
+    username: String,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Print a registration link with a new invite code
+#[argh(subcommand, name = "create-invite")]
+struct CreateInvite {}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// List accounts waiting for approval
+#[argh(subcommand, name = "pending")]
+struct Pending {}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Let a pending account log in
+#[argh(subcommand, name = "approve")]
+struct Approve {
+    #[argh(positional)]
+    username: String,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Delete a pending account
+#[argh(subcommand, name = "reject")]
+struct Reject {
+    #[argh(positional)]
+    username: String,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Show who invited a user, and everyone they invited
+#[argh(subcommand, name = "invite-tree")]
+struct InviteTree {
+    #[argh(positional)]
+    username: String,
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
@@ -33,15 +187,133 @@ struct RunServer {
     config: String,
 }
 
+#[derive(FromArgs, PartialEq, Debug)]
+/// Validate a config file without starting the server
+#[argh(subcommand, name = "check-config")]
+struct CheckConfig {
+    /// config file path
+    #[argh(option, short = 'c', default = "default_config()")]
+    config: String,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Bring the database schema up to date. `run` does this too
+#[argh(subcommand, name = "migrate")]
+struct Migrate {
+    /// config file path
+    #[argh(option, short = 'c', default = "default_config()")]
+    config: String,
+}
+
+fn run_migrate(migrate: Migrate) {
+    let config = load_config(&migrate.config);
+    let result = db::open(&config)
+        .map_err(db::MigrationError::from)
+        .and_then(|mut conn| db::migrate(&mut conn));
+    match result {
+        Ok(0) => println!("Already at schema version {}", db::latest_version()),
+        Ok(ran) => println!(
+            "Ran {} migrations, now at schema version {}",
+            ran,
+            db::latest_version()
+        ),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn load_config(path: &str) -> Config {
+    match Config::load(path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn run_admin(admin: Admin) {
+    let config = load_config(&admin.config);
+    let print_lines = |lines: Vec<String>| {
+        for line in lines {
+            println!("{}", line);
+        }
+    };
+    let result = match admin.command {
+        AdminCommand::CreateUser(c) => admin::create_user(&config, &c.username, c.email.as_deref())
+            .map(|password| {
+                println!(
+                    "Created {} with password {}",
+                    c.username.to_lowercase(),
+                    password
+                )
+            }),
+        AdminCommand::DeleteUser(d) => {
+            admin::delete_user(&config, &d.username).map(|_| println!("Deleted {}", d.username))
+        }
+        AdminCommand::ResetPassword(r) => admin::reset_password(&config, &r.username)
+            .map(|password| println!("New password for {}: {}", r.username, password)),
+        AdminCommand::ListUsers(_) => admin::list_users(&config).map(print_lines),
+        AdminCommand::Lock(l) => {
+            admin::set_locked(&config, &l.username, true).map(|_| println!("Locked {}", l.username))
+        }
+        AdminCommand::Unlock(u) => admin::set_locked(&config, &u.username, false)
+            .map(|_| println!("Unlocked {}", u.username)),
+        AdminCommand::Rename(r) => admin::rename_user(&config, &r.username, &r.new_username)
+            .map(|_| println!("Renamed {} to {}", r.username, r.new_username)),
+        AdminCommand::DiskUsage(_) => admin::disk_usage(&config).map(print_lines),
+        AdminCommand::SetQuota(s) => parse_quota(&s.quota)
+            .and_then(|quota| admin::set_quota(&config, &s.username, quota))
+            .map(|_| println!("Set quota for {}", s.username)),
+        AdminCommand::PurgeProxyCache(_) => admin::purge_proxy_cache(&config)
+            .map(|purged| println!("Purged {} cached pages", purged)),
+        AdminCommand::ResetLink(r) => {
+            admin::reset_link(&config, &r.username).map(|link| println!("{}", link))
+        }
+        AdminCommand::CreateInvite(_) => {
+            admin::create_invite(&config).map(|link| println!("{}", link))
+        }
+        AdminCommand::Pending(_) => admin::pending(&config).map(print_lines),
+        AdminCommand::Approve(a) => {
+            admin::approve(&config, &a.username).map(|_| println!("Approved {}", a.username))
+        }
+        AdminCommand::Reject(r) => {
+            admin::reject(&config, &r.username).map(|_| println!("Deleted {}", r.username))
+        }
+        AdminCommand::InviteTree(t) => admin::invite_tree(&config, &t.username).map(print_lines),
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
 fn default_config() -> String {
     return "flounder.toml".to_string();
 }
 /// Command line entrypoint
 fn main() {
     let arg: Arguments = argh::from_env();
-    match arg.sub {
-        Sub::RunServer(r) => run_server(r.config),
-        _ => Ok(()),
+    let result = match arg.sub {
+        Sub::RunServer(r) => run_server(load_config(&r.config)),
+        Sub::CheckConfig(c) => {
+            load_config(&c.config);
+            println!("{} is valid", c.config);
+            Ok(())
+        }
+        Sub::Admin(a) => {
+            run_admin(a);
+            Ok(())
+        }
+        Sub::Migrate(m) => {
+            run_migrate(m);
+            Ok(())
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
     }
-    .ok();
 }
@@ -0,0 +1,131 @@
+/// Changing passwords, and single-use reset tokens for when the old one is lost
+use crate::session::{self, random_hex};
+use crate::Config;
+use openssl::hash::{hash, MessageDigest};
+use rusqlite::{params, Connection, OptionalExtension};
+
+// Reset links stop working after this many seconds
+const RESET_TOKEN_LIFETIME: i64 = 60 * 60;
+
+/// Same rules as registration
+pub fn password_errors(password: &str, password2: &str) -> Vec<&'static str> {
+    let mut errors = vec![];
+    if password.len() < 6 {
+        errors.push("Please use a password at least 6 characters long. Preferably longer.");
+    }
+    if password != password2 {
+        errors.push("Passwords do not match");
+    }
+    errors
+}
+
+/// Only a hash of each token is stored, so a leaked database doesn't leak working links
+fn token_hash(token: &str) -> String {
+    hash(MessageDigest::sha256(), token.as_bytes())
+        .map(|digest| digest.iter().map(|b| format!("{:02x}", b)).collect())
+        .unwrap_or_default()
+}
+
+/// Sets the password and ends every session, so a stolen one stops working too
+pub fn set_password(conn: &Connection, user_id: &str, password: &str) -> rusqlite::Result<()> {
+    // bcrypt only fails on invalid cost
+    let password_hash = bcrypt::hash(password, bcrypt::DEFAULT_COST).unwrap();
+    conn.execute(
+        "UPDATE user SET password_hash = (?1) WHERE id = (?2)",
+        &[&password_hash, user_id],
+    )?;
+    session::delete_all(conn, user_id)
+}
+
+/// Replaces any earlier token for the user
+pub fn create_reset_token(conn: &Connection, user_id: i64) -> rusqlite::Result<String> {
+    let token = random_hex(32);
+    conn.execute(
+        "DELETE FROM password_reset WHERE user_id = (?1) OR expires_at <= strftime('%s', 'now')",
+        &[user_id],
+    )?;
+    conn.execute(
+        r#"
+        INSERT INTO password_reset (token_hash, user_id, expires_at)
+        VALUES (?1, ?2, strftime('%s', 'now') + (?3))
+        "#,
+        params![token_hash(&token), user_id, RESET_TOKEN_LIFETIME],
+    )?;
+    Ok(token)
+}
+
+/// The user a token was issued to, if it's still good. Doesn't use it up
+pub fn reset_token_user(conn: &Connection, token: &str) -> rusqlite::Result<Option<String>> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+        SELECT user_id FROM password_reset
+        WHERE token_hash = (?1) AND expires_at > strftime('%s', 'now')
+        "#,
+    )?;
+    let user_id: Option<i64> = stmt
+        .query_row(&[token_hash(token)], |row| row.get(0))
+        .optional()?;
+    Ok(user_id.map(|id| id.to_string()))
+}
+
+/// Sets the new password if the token is good, and uses the token up. Returns whether it was
+pub fn redeem_reset_token(
+    conn: &Connection,
+    token: &str,
+    password: &str,
+) -> rusqlite::Result<bool> {
+    let user_id = match reset_token_user(conn, token)? {
+        Some(user_id) => user_id,
+        None => return Ok(false),
+    };
+    conn.execute(
+        "DELETE FROM password_reset WHERE token_hash = (?1)",
+        &[token_hash(token)],
+    )?;
+    set_password(conn, &user_id, password)?;
+    Ok(true)
+}
+
+pub fn reset_link(config: &Config, token: &str) -> String {
+    format!("{}/reset_password/{}", config.base_url(), token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::NO_PARAMS;
+
+    #[test]
+    fn test_reset_token_single_use() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        crate::db::migrate(&mut conn).unwrap();
+        conn.execute(
+            "INSERT INTO user (id, username) VALUES (3, 'alice')",
+            NO_PARAMS,
+        )
+        .unwrap();
+        let old = create_reset_token(&conn, 3).unwrap();
+        let token = create_reset_token(&conn, 3).unwrap();
+        assert_eq!(reset_token_user(&conn, &old).unwrap(), None);
+        assert_eq!(
+            reset_token_user(&conn, &token).unwrap(),
+            Some("3".to_string())
+        );
+
+        assert!(redeem_reset_token(&conn, &token, "hunter22").unwrap());
+        assert!(!redeem_reset_token(&conn, &token, "hunter23").unwrap());
+        let password_hash: String = conn
+            .query_row(
+                "SELECT password_hash FROM user WHERE id = 3",
+                NO_PARAMS,
+                |r| r.get(0),
+            )
+            .unwrap();
+        assert!(bcrypt::verify("hunter22", &password_hash).unwrap());
+
+        let expired = create_reset_token(&conn, 3).unwrap();
+        conn.execute("UPDATE password_reset SET expires_at = 0", NO_PARAMS)
+            .unwrap();
+        assert!(!redeem_reset_token(&conn, &expired, "hunter24").unwrap());
+    }
+}
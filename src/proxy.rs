@@ -0,0 +1,248 @@
+/// Gemini-to-HTTP proxy helpers. Proxied URLs look like {proxy_url}{host}[:port]/{path}[?query],
+/// the same shape portal.mozz.us uses, so relative links in a proxied page resolve by themselves
+use crate::client::GeminiResponse;
+use crate::Config;
+use rusqlite::{params, Connection, OptionalExtension, NO_PARAMS};
+use url::Url;
+
+/// The gemini:// URL behind the part of a proxy path after the prefix
+pub fn gemini_url(proxy_path: &str, query: &str) -> Option<Url> {
+    // tolerate a pasted gemini:// URL. Slashes may have been merged on the way here
+    let proxy_path = proxy_path
+        .trim_start_matches("gemini:")
+        .trim_start_matches('/');
+    if proxy_path.is_empty() {
+        return None;
+    }
+    let mut url = Url::parse(&format!("gemini://{}", proxy_path)).ok()?;
+    url.host_str()?;
+    if !query.is_empty() {
+        url.set_query(Some(query));
+    }
+    Some(url)
+}
+
+/// Where a gemini:// URL lives inside the proxy
+pub fn proxied_url(proxy_url: &str, url: &Url) -> String {
+    let mut proxied = format!(
+        "{}/{}",
+        proxy_url.trim_end_matches('/'),
+        url.host_str().unwrap_or("")
+    );
+    if let Some(port) = url.port() {
+        proxied.push_str(&format!(":{}", port));
+    }
+    proxied.push_str(url.path());
+    if let Some(query) = url.query() {
+        proxied.push('?');
+        proxied.push_str(query);
+    }
+    proxied
+}
+
+/// Points gemini:// link lines at the proxy. With a base, relative links are resolved against it
+/// first, so they stay in the proxy too; without one they are left alone.
+pub fn proxy_links(gmi: &str, base: Option<&Url>, proxy_url: &str) -> String {
+    let mut out = String::with_capacity(gmi.len());
+    let mut preformatted = false;
+    for line in gmi.lines() {
+        if line.starts_with("```") {
+            preformatted = !preformatted;
+        }
+        let rewritten = if !preformatted && line.starts_with("=>") {
+            rewrite_link(line, base, proxy_url)
+        } else {
+            None
+        };
+        out.push_str(rewritten.as_deref().unwrap_or(line));
+        out.push('\n');
+    }
+    out
+}
+
+fn rewrite_link(line: &str, base: Option<&Url>, proxy_url: &str) -> Option<String> {
+    let rest = line[2..].trim();
+    let mut parts = rest.splitn(2, char::is_whitespace);
+    let link = parts.next().filter(|l| !l.is_empty())?;
+    let label = parts.next().map(|l| l.trim()).unwrap_or("");
+    let url = match base {
+        Some(base) => base.join(link).ok()?,
+        None => Url::parse(link).ok()?,
+    };
+    let target = if url.scheme() == "gemini" {
+        proxied_url(proxy_url, &url)
+    } else {
+        url.to_string()
+    };
+    // keep showing the original link when there was no label
+    let label = if label.is_empty() { link } else { label };
+    Some(format!("=> {} {}", target, label))
+}
+
+/// A fresh cached response for url, if there is one. Only 20 responses are ever stored
+pub fn cached_response(
+    conn: &Connection,
+    url: &str,
+    config: &Config,
+) -> rusqlite::Result<Option<GeminiResponse>> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+        SELECT final_url, meta, body FROM proxy_cache
+        WHERE url = (?1) AND fetched_at > strftime('%s', 'now') - (?2)
+        "#,
+    )?;
+    let row: Option<(String, String, Vec<u8>)> = stmt
+        .query_row(params![url, config.proxy_cache_ttl as i64], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })
+        .optional()?;
+    Ok(row.and_then(|(final_url, meta, body)| {
+        Some(GeminiResponse {
+            url: Url::parse(&final_url).ok()?,
+            status: 20,
+            meta: meta,
+            body: body,
+        })
+    }))
+}
+
+/// Stores a 20 response under the URL that was asked for, then evicts the oldest entries
+/// until the cache fits in proxy_cache_max_entries and proxy_cache_max_bytes
+pub fn cache_response(
+    conn: &Connection,
+    url: &str,
+    response: &GeminiResponse,
+    config: &Config,
+) -> rusqlite::Result<()> {
+    if response.status != 20
+        || config.proxy_cache_ttl == 0
+        || response.body.len() as u64 > config.proxy_cache_max_bytes
+    {
+        return Ok(());
+    }
+    conn.execute(
+        r#"
+        INSERT OR REPLACE INTO proxy_cache (url, final_url, meta, body, size)
+        VALUES (?1, ?2, ?3, ?4, ?5)
+        "#,
+        params![
+            url,
+            response.url.as_str(),
+            response.meta,
+            response.body,
+            response.body.len() as i64
+        ],
+    )?;
+    conn.execute(
+        "DELETE FROM proxy_cache WHERE fetched_at <= strftime('%s', 'now') - (?1)",
+        &[config.proxy_cache_ttl as i64],
+    )?;
+    let mut stmt = conn.prepare_cached(
+        r#"
+        SELECT url, size FROM proxy_cache
+        ORDER BY fetched_at DESC, rowid DESC
+        "#,
+    )?;
+    let rows = stmt.query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))?;
+    let mut kept_bytes = 0;
+    let mut evict = vec![];
+    for (i, row) in rows.enumerate() {
+        let (url, size): (String, i64) = row?;
+        kept_bytes += size as u64;
+        if i as u32 >= config.proxy_cache_max_entries || kept_bytes > config.proxy_cache_max_bytes {
+            evict.push(url);
+        }
+    }
+    for url in evict {
+        conn.execute("DELETE FROM proxy_cache WHERE url = (?1)", &[url])?;
+    }
+    Ok(())
+}
+
+/// Empties the cache. Returns how many entries were dropped
+pub fn purge_cache(conn: &Connection) -> rusqlite::Result<usize> {
+    conn.execute("DELETE FROM proxy_cache", NO_PARAMS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn test_gemini_url() {
+        assert_eq!(
+            gemini_url("example.org/a/b.gmi", "q=1").unwrap().as_str(),
+            "gemini://example.org/a/b.gmi?q=1"
+        );
+        assert_eq!(
+            gemini_url("gemini:/example.org:1966/", "")
+                .unwrap()
+                .as_str(),
+            "gemini://example.org:1966/"
+        );
+        assert!(gemini_url("", "").is_none());
+    }
+
+    #[test]
+    fn test_proxy_links() {
+        let base = Url::parse("gemini://example.org/blog/post.gmi").unwrap();
+        let gmi = "# hi\n=> other.gmi\n=> gemini://x.org:1966/?q Search\n=> https://web.site Web\n```\n=> raw\n```";
+        assert_eq!(
+            proxy_links(gmi, Some(&base), "https://flounder.local/proxy/"),
+            "# hi\n\
+             => https://flounder.local/proxy/example.org/blog/other.gmi other.gmi\n\
+             => https://flounder.local/proxy/x.org:1966/?q Search\n\
+             => https://web.site/ Web\n\
+             ```\n=> raw\n```\n"
+        );
+        assert_eq!(
+            proxy_links("=> /relative\n", None, "https://flounder.local/proxy/"),
+            "=> /relative\n"
+        );
+    }
+
+    #[test]
+    fn test_cache_bounds() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        crate::db::migrate(&mut conn).unwrap();
+        let mut config = crate::config::test_config(Path::new("tmp"));
+        config.proxy_cache_max_entries = 2;
+        config.proxy_cache_max_bytes = 10;
+        let response = |status, body: &str| GeminiResponse {
+            url: Url::parse("gemini://example.org/").unwrap(),
+            status: status,
+            meta: "text/gemini".to_string(),
+            body: body.as_bytes().to_vec(),
+        };
+        cache_response(&conn, "gemini://a/", &response(20, "aaaa"), &config).unwrap();
+        cache_response(&conn, "gemini://b/", &response(51, ""), &config).unwrap();
+        assert!(cached_response(&conn, "gemini://a/", &config)
+            .unwrap()
+            .is_some());
+        assert!(cached_response(&conn, "gemini://b/", &config)
+            .unwrap()
+            .is_none());
+
+        // over the entry bound
+        cache_response(&conn, "gemini://c/", &response(20, "c"), &config).unwrap();
+        cache_response(&conn, "gemini://d/", &response(20, "d"), &config).unwrap();
+        assert!(cached_response(&conn, "gemini://a/", &config)
+            .unwrap()
+            .is_none());
+        // over the byte bound
+        cache_response(&conn, "gemini://e/", &response(20, "eeeeeeeeee"), &config).unwrap();
+        assert!(cached_response(&conn, "gemini://d/", &config)
+            .unwrap()
+            .is_none());
+        assert!(cached_response(&conn, "gemini://e/", &config)
+            .unwrap()
+            .is_some());
+
+        config.proxy_cache_ttl = 0;
+        assert!(cached_response(&conn, "gemini://e/", &config)
+            .unwrap()
+            .is_none());
+        assert_eq!(purge_cache(&conn).unwrap(), 1);
+    }
+}
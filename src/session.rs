@@ -0,0 +1,172 @@
+/// Server-side login sessions
+use crate::Config;
+use rand::RngCore;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::io;
+use std::path::Path;
+
+// actix-identity wants at least this many bytes to sign cookies with
+const KEY_LENGTH: usize = 32;
+
+pub fn random_hex(len: usize) -> String {
+    let mut bytes = vec![0; len];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Starts a session for the user and returns its ID. Expired sessions get cleaned up here too.
+/// The ID is all the identity cookie carries; who it belongs to and when it runs out stay here
+pub fn create(conn: &Connection, user_id: i64, config: &Config) -> rusqlite::Result<String> {
+    conn.execute(
+        "DELETE FROM session WHERE expires_at <= strftime('%s', 'now')",
+        rusqlite::NO_PARAMS,
+    )?;
+    let session_id = random_hex(32);
+    conn.execute(
+        r#"
+        INSERT INTO session (id, user_id, csrf_token, expires_at)
+        VALUES (?1, ?2, ?3, strftime('%s', 'now') + (?4))
+        "#,
+        params![
+            session_id,
+            user_id,
+            random_hex(32),
+            config.session_lifetime_secs() as i64
+        ],
+    )?;
+    Ok(session_id)
+}
+
+/// (user id, username) of an unexpired session. Locked users have none
+pub fn lookup(conn: &Connection, session_id: &str) -> rusqlite::Result<Option<(String, String)>> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+        SELECT user.id, user.username FROM session
+        JOIN user
+        ON session.user_id = user.id
+        WHERE session.id = (?1) AND session.expires_at > strftime('%s', 'now')
+        AND user.locked = 0
+        "#,
+    )?;
+    stmt.query_row(&[session_id], |row| {
+        let user_id: i64 = row.get(0)?;
+        Ok((user_id.to_string(), row.get(1)?))
+    })
+    .optional()
+}
+
+/// What forms rendered for this session must send back. See csrf.rs
+pub fn csrf_token(conn: &Connection, session_id: &str) -> rusqlite::Result<Option<String>> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+        SELECT csrf_token FROM session
+        WHERE id = (?1) AND expires_at > strftime('%s', 'now')
+        "#,
+    )?;
+    stmt.query_row(&[session_id], |row| row.get(0)).optional()
+}
+
+pub fn delete(conn: &Connection, session_id: &str) -> rusqlite::Result<()> {
+    conn.execute("DELETE FROM session WHERE id = (?1)", &[session_id])?;
+    Ok(())
+}
+
+/// Logs the user out everywhere
+pub fn delete_all(conn: &Connection, user_id: &str) -> rusqlite::Result<()> {
+    conn.execute("DELETE FROM session WHERE user_id = (?1)", &[user_id])?;
+    Ok(())
+}
+
+/// The cookie signing key: session_key from config if set, otherwise the one in session_key_path,
+/// which is generated on first run
+pub fn signing_key(config: &Config) -> io::Result<Vec<u8>> {
+    if let Some(key) = &config.session_key {
+        // validated when the config was loaded
+        return Ok(decode_hex(key).unwrap_or_default());
+    }
+    let path = Path::new(&config.session_key_path);
+    if path.exists() {
+        let key = std::fs::read_to_string(path)?;
+        return decode_hex(key.trim())
+            .filter(|key| key.len() >= KEY_LENGTH)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} is not a valid session key", path.display()),
+                )
+            });
+    }
+    let key = random_hex(KEY_LENGTH);
+    write_private(path, &key)?;
+    log::info!("Generated a new session key in {}", path.display());
+    Ok(decode_hex(&key).unwrap_or_default())
+}
+
+#[cfg(unix)]
+fn write_private(path: &Path, contents: &str) -> io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(contents.as_bytes())
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, contents: &str) -> io::Result<()> {
+    std::fs::write(path, contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sessions() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        crate::db::migrate(&mut conn).unwrap();
+        conn.execute(
+            "INSERT INTO user (id, username) VALUES (7, 'alice')",
+            rusqlite::NO_PARAMS,
+        )
+        .unwrap();
+        let config = crate::config::test_config(Path::new("tmp"));
+        let first = create(&conn, 7, &config).unwrap();
+        let second = create(&conn, 7, &config).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(
+            lookup(&conn, &first).unwrap(),
+            Some(("7".to_string(), "alice".to_string()))
+        );
+        assert_eq!(lookup(&conn, "forged").unwrap(), None);
+        assert_ne!(
+            csrf_token(&conn, &first).unwrap(),
+            csrf_token(&conn, &second).unwrap()
+        );
+
+        delete(&conn, &first).unwrap();
+        assert_eq!(lookup(&conn, &first).unwrap(), None);
+        assert!(lookup(&conn, &second).unwrap().is_some());
+        delete_all(&conn, "7").unwrap();
+        assert_eq!(lookup(&conn, &second).unwrap(), None);
+    }
+
+    #[test]
+    fn test_decode_hex() {
+        assert_eq!(decode_hex("00ff10"), Some(vec![0, 255, 16]));
+        assert_eq!(decode_hex("0g"), None);
+        assert_eq!(decode_hex("abc"), None);
+    }
+}
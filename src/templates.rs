@@ -1,9 +1,11 @@
-use actix_web::{error::ErrorInternalServerError, HttpResponse};
+use actix_web::HttpResponse;
 use askama::*;
 use bytes::BytesMut;
 
 use crate::error::FlounderError;
+use crate::invite::Invite;
 use crate::twtxt::TwtxtStatus;
+use crate::utils::{DiffLine, TreeEntry};
 
 pub trait TemplateIntoResponse {
     fn into_response(&self) -> ::std::result::Result<HttpResponse, FlounderError>;
@@ -12,8 +14,7 @@ pub trait TemplateIntoResponse {
 impl<T: askama::Template> TemplateIntoResponse for T {
     fn into_response(&self) -> std::result::Result<HttpResponse, FlounderError> {
         let mut buffer = BytesMut::with_capacity(self.size_hint());
-        self.render_into(&mut buffer)
-            .map_err(|_| ErrorInternalServerError("Template parsing error"))?;
+        self.render_into(&mut buffer)?;
 
         let ctype = "text/html";
         Ok(HttpResponse::Ok().content_type(ctype).body(buffer.freeze()))
@@ -27,6 +28,7 @@ pub struct IndexTemplate<'a> {
     pub server_name: &'a str,
     pub files: Vec<RenderedFile>, // arr?
     pub users: Vec<String>,
+    pub csrf_token: &'a str,
 }
 
 pub struct RenderedFile {
@@ -41,13 +43,20 @@ pub struct MySiteTemplate<'a> {
     pub logged_in: bool,
     pub server_name: &'a str,
     pub username: &'a str,
-    pub files: Vec<RenderedFile>, // arr?
+    pub files: Vec<TreeEntry>,
     pub errors: Vec<String>,
+    pub usage: String,
+    pub quota: String,
+    pub csrf_token: &'a str,
+    pub unverified: bool,
+    pub can_publish: bool,
+    pub invites_enabled: bool,
 }
 #[derive(Template)]
 #[template(path = "login.html")]
 pub struct LoginTemplate<'a> {
     pub errors: Vec<&'a str>,
+    pub csrf_token: &'a str,
 }
 
 #[derive(Template)]
@@ -55,6 +64,24 @@ pub struct LoginTemplate<'a> {
 pub struct RegisterTemplate<'a> {
     pub server_name: &'a str,
     pub errors: Vec<&'a str>,
+    pub csrf_token: &'a str,
+    pub invite_code: &'a str,
+    pub invite_field: bool,
+    pub invite_required: bool,
+}
+
+#[derive(Template)]
+#[template(path = "pending.html")]
+pub struct PendingTemplate {}
+
+#[derive(Template)]
+#[template(path = "invites.html")]
+pub struct InvitesTemplate<'a> {
+    pub logged_in: bool,
+    pub csrf_token: &'a str,
+    pub errors: Vec<String>,
+    pub invites: Vec<Invite>,
+    pub register_url: &'a str,
 }
 
 #[derive(Template)]
@@ -63,6 +90,33 @@ pub struct EditFileTemplate<'a> {
     pub server_name: &'a str,
     pub filename: &'a str,
     pub file_text: &'a str,
+    pub csrf_token: &'a str,
+}
+
+pub struct RenderedRevision {
+    pub id: u32,
+    pub size: String,
+    pub time_ago: String,
+}
+
+#[derive(Template)]
+#[template(path = "revisions.html")]
+pub struct RevisionsTemplate<'a> {
+    pub logged_in: bool,
+    pub filename: &'a str,
+    pub revisions: Vec<RenderedRevision>,
+    pub csrf_token: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "diff.html")]
+pub struct DiffTemplate<'a> {
+    pub logged_in: bool,
+    pub filename: &'a str,
+    pub old_id: u32,
+    pub new_id: Option<u32>,
+    pub lines: Vec<DiffLine>,
+    pub csrf_token: &'a str,
 }
 
 #[derive(Template)]
@@ -78,10 +132,61 @@ pub struct ErrorTemplate {
     pub error: String,
 }
 
+#[derive(Template)]
+#[template(path = "change_password.html")]
+pub struct ChangePasswordTemplate<'a> {
+    pub logged_in: bool,
+    pub csrf_token: &'a str,
+    pub errors: Vec<&'a str>,
+}
+
+#[derive(Template)]
+#[template(path = "forgot_password.html")]
+pub struct ForgotPasswordTemplate<'a> {
+    pub csrf_token: &'a str,
+    pub mail_enabled: bool,
+    pub sent: bool,
+}
+
+#[derive(Template)]
+#[template(path = "reset_password.html")]
+pub struct ResetPasswordTemplate<'a> {
+    pub csrf_token: &'a str,
+    pub token: &'a str,
+    pub valid: bool,
+    pub errors: Vec<&'a str>,
+}
+
+/// Shown for Gemini 1x responses, which ask the user for a line of input
+#[derive(Template)]
+#[template(path = "proxy_input.html")]
+pub struct ProxyInputTemplate<'a> {
+    pub prompt: &'a str,
+    pub sensitive: bool,
+}
+
+/// Shown for Gemini 3x responses that point somewhere other than gemini://, instead of
+/// following them
+#[derive(Template)]
+#[template(path = "proxy_redirect.html")]
+pub struct ProxyRedirectTemplate<'a> {
+    pub url: &'a str,
+    pub location: &'a str,
+    // only http(s) gets a link; anything else, like javascript:, is shown as text
+    pub linkable: bool,
+}
+
 #[derive(Template)]
 #[template(path = "statuses.html")]
 pub struct StatusesTemplate<'a> {
     pub logged_in: bool,
     pub statuses: Vec<TwtxtStatus>,
     pub server_name: &'a str,
+    pub csrf_token: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "verify_email.html")]
+pub struct VerifyEmailTemplate {
+    pub verified: bool,
 }
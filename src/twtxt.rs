@@ -54,7 +54,10 @@ mod tests {
 
     #[test]
     fn test_invalid_status() {
-        let new_status = TwtxtStatus::new("guy", "1996-19T16:39:57-08:00\they whats up");
+        let new_status = TwtxtStatus::new(
+            "guy".to_owned(),
+            "1996-19T16:39:57-08:00\they whats up".to_owned(),
+        );
         assert!(new_status.is_none())
     }
 }
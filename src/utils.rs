@@ -1,3 +1,5 @@
+use openssl::ssl::{SslAcceptor, SslAcceptorBuilder, SslFiletype, SslMethod};
+use std::io;
 use std::path::Path;
 use std::time::SystemTime;
 
@@ -6,6 +8,29 @@ static ALLOWED_EXTENSIONS: &[&'static str] = &[
     "mp3",
 ];
 
+/// Same rules for registration and for `flounder admin`
+pub fn username_errors(username: &str) -> Vec<&'static str> {
+    let mut errors = vec![];
+    if username.len() > 32
+        || username == ""
+        || &username.to_lowercase() == "www"
+        || &username.to_lowercase() == "proxy"
+    {
+        errors.push("Invalid username")
+    }
+    if !username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-')
+    {
+        errors.push("Username must only contain a-z characters and hyphens");
+    }
+    errors
+}
+
+pub fn to_io_error<E: std::error::Error + Send + Sync + 'static>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
 pub fn ok_extension(filename: &str) -> bool {
     let tmp = filename.to_lowercase();
     let lower_extension: Option<&str> = Path::new(&tmp).extension().and_then(|s| s.to_str());
@@ -14,6 +39,157 @@ pub fn ok_extension(filename: &str) -> bool {
         .any(|s| Some(*s) == lower_extension);
 }
 
+// blog/2026/post.gmi is 3
+const MAX_PATH_DEPTH: usize = 8;
+
+/// Turns a user supplied path like "blog//2026/./post.gmi" into "blog/2026/post.gmi".
+/// Each segment is sanitized on its own so directories survive. None if the path
+/// tries to climb out of the user's directory or has nothing left in it.
+pub fn normalize_user_path(path: &str) -> Option<String> {
+    let mut segments = vec![];
+    for segment in path.split(|c| c == '/' || c == '\\') {
+        match segment {
+            "" | "." => continue,
+            ".." => return None,
+            s => {
+                let clean = sanitize_filename::sanitize(s);
+                if clean.is_empty() {
+                    return None;
+                }
+                segments.push(clean);
+            }
+        }
+    }
+    if segments.is_empty() || segments.len() > MAX_PATH_DEPTH {
+        return None;
+    }
+    Some(segments.join("/"))
+}
+
+/// Like normalize_user_path, but a trailing slash (or nothing) means the index.gmi in that directory
+pub fn normalize_request_path(path: &str) -> Option<String> {
+    if path.is_empty() || path.ends_with('/') {
+        return normalize_user_path(&format!("{}index.gmi", path));
+    }
+    normalize_user_path(path)
+}
+
+pub struct TreeEntry {
+    pub depth: usize,
+    pub name: String,
+    pub user_path: Option<String>, // None for directories
+}
+
+/// Flattens sorted paths into rows for an indented listing, with a row for each directory
+pub fn file_tree(sorted_paths: &[String]) -> Vec<TreeEntry> {
+    let mut entries = vec![];
+    let mut current_dirs: Vec<&str> = vec![];
+    for path in sorted_paths {
+        let mut segments: Vec<&str> = path.split('/').collect();
+        let name = segments.pop().unwrap_or("");
+        let common = current_dirs
+            .iter()
+            .zip(segments.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+        for (depth, dir) in segments.iter().enumerate().skip(common) {
+            entries.push(TreeEntry {
+                depth: depth,
+                name: format!("{}/", dir),
+                user_path: None,
+            });
+        }
+        entries.push(TreeEntry {
+            depth: segments.len(),
+            name: name.to_string(),
+            user_path: Some(path.clone()),
+        });
+        current_dirs = segments;
+    }
+    entries
+}
+
+/// MIME type for an allowed extension, defaulting to octet-stream
+pub fn mime_type(filename: &str) -> &'static str {
+    let tmp = filename.to_lowercase();
+    let lower_extension: Option<&str> = Path::new(&tmp).extension().and_then(|s| s.to_str());
+    match lower_extension {
+        Some("gmi") | Some("gemini") => "text/gemini",
+        Some("txt") => "text/plain",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("png") => "image/png",
+        Some("svg") => "image/svg+xml",
+        Some("webp") => "image/webp",
+        Some("midi") => "audio/midi",
+        Some("json") => "application/json",
+        Some("csv") => "text/csv",
+        Some("mp3") => "audio/mpeg",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Which capsule a hostname belongs to. Outer None means we don't serve that host at all,
+/// inner None means the main host.
+pub fn capsule_for_host<'a>(host: &'a str, server_host: &str) -> Option<Option<&'a str>> {
+    if host == server_host {
+        return Some(None);
+    }
+    let username = host.strip_suffix(server_host)?.strip_suffix('.')?;
+    if username.is_empty() || username.contains('.') {
+        return None;
+    }
+    Some(Some(username))
+}
+
+/// TLS acceptor from PEM files, shared by the HTTP and Gemini listeners
+pub fn ssl_acceptor(cert_path: &str, key_path: &str) -> io::Result<SslAcceptorBuilder> {
+    let to_io_error = |e| io::Error::new(io::ErrorKind::Other, e);
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).map_err(to_io_error)?;
+    builder
+        .set_private_key_file(key_path, SslFiletype::PEM)
+        .map_err(to_io_error)?;
+    builder
+        .set_certificate_chain_file(cert_path)
+        .map_err(to_io_error)?;
+    Ok(builder)
+}
+
+pub struct DiffLine {
+    pub kind: &'static str, // added, removed or same. Used as a css class
+    pub text: String,
+}
+
+pub fn line_diff(old: &str, new: &str) -> Vec<DiffLine> {
+    diff::lines(old, new)
+        .into_iter()
+        .map(|line| match line {
+            diff::Result::Left(text) => DiffLine {
+                kind: "removed",
+                text: format!("- {}", text),
+            },
+            diff::Result::Both(text, _) => DiffLine {
+                kind: "same",
+                text: format!("  {}", text),
+            },
+            diff::Result::Right(text) => DiffLine {
+                kind: "added",
+                text: format!("+ {}", text),
+            },
+        })
+        .collect()
+}
+
+pub fn rendered_size(bytes: i64) -> String {
+    if bytes < 1024 {
+        format!("{} B", bytes)
+    } else if bytes < 1024 * 1024 {
+        format!("{:.1} KB", bytes as f64 / 1024.0)
+    } else {
+        format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0))
+    }
+}
+
 pub fn rendered_time_ago(epoch_time: u32) -> String {
     // do some fun stuff
     let now = SystemTime::now()
@@ -32,3 +208,81 @@ pub fn rendered_time_ago(epoch_time: u32) -> String {
         return format!("{} days ago", ago / (3600 * 24));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_user_path() {
+        assert_eq!(
+            normalize_user_path("blog//2026/./post.gmi"),
+            Some("blog/2026/post.gmi".to_string())
+        );
+        assert_eq!(
+            normalize_user_path("/index.gmi"),
+            Some("index.gmi".to_string())
+        );
+        assert_eq!(normalize_user_path("../bob/index.gmi"), None);
+        assert_eq!(normalize_user_path("blog/../../bob/index.gmi"), None);
+        assert_eq!(normalize_user_path("blog\\..\\..\\x.gmi"), None);
+        assert_eq!(normalize_user_path("/"), None);
+        assert_eq!(
+            normalize_request_path("blog/"),
+            Some("blog/index.gmi".to_string())
+        );
+        assert_eq!(normalize_request_path(""), Some("index.gmi".to_string()));
+    }
+
+    #[test]
+    fn test_line_diff() {
+        let kinds: Vec<&str> = line_diff("a\nb\nc", "a\nc\nd")
+            .iter()
+            .map(|l| l.kind)
+            .collect();
+        assert_eq!(kinds, vec!["same", "removed", "same", "added"]);
+    }
+
+    #[test]
+    fn test_file_tree() {
+        let paths: Vec<String> = vec!["a/b/c.gmi", "a/d.gmi", "index.gmi"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let tree: Vec<(usize, String)> = file_tree(&paths)
+            .into_iter()
+            .map(|e| (e.depth, e.name))
+            .collect();
+        assert_eq!(
+            tree,
+            vec![
+                (0, "a/".to_string()),
+                (1, "b/".to_string()),
+                (2, "c.gmi".to_string()),
+                (1, "d.gmi".to_string()),
+                (0, "index.gmi".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_capsule_for_host() {
+        assert_eq!(
+            capsule_for_host("flounder.local", "flounder.local"),
+            Some(None)
+        );
+        assert_eq!(
+            capsule_for_host("alice.flounder.local", "flounder.local"),
+            Some(Some("alice"))
+        );
+        assert_eq!(
+            capsule_for_host("a.b.flounder.local", "flounder.local"),
+            None
+        );
+        assert_eq!(
+            capsule_for_host("aliceflounder.local", "flounder.local"),
+            None
+        );
+        assert_eq!(capsule_for_host("example.com", "flounder.local"), None);
+    }
+}
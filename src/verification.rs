@@ -0,0 +1,122 @@
+/// Email verification through signed links
+use crate::csrf::tokens_match;
+use crate::mailer::Email;
+use crate::Config;
+use chrono::Utc;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use rusqlite::{params, Connection, OptionalExtension};
+
+// Verification links stop working after this many seconds
+const LINK_LIFETIME: i64 = 7 * 24 * 60 * 60;
+
+/// Signs links with the session key. Registered as app data by run_server
+pub struct LinkSigner {
+    key: Vec<u8>,
+}
+
+impl LinkSigner {
+    pub fn new(key: &[u8]) -> LinkSigner {
+        LinkSigner { key: key.to_vec() }
+    }
+
+    /// HMAC over the user ID, expiry and address, so nothing is stored until the link is
+    /// followed, and changing the address voids links sent to the old one
+    fn signature(&self, user_id: i64, email: &str, expires_at: i64) -> String {
+        // the prefix keeps these from doubling as any other MAC made with the same key
+        let message = format!("verify-email\n{}\n{}\n{}", user_id, expires_at, email);
+        // HMAC over an in-memory key has no way to fail
+        let key = PKey::hmac(&self.key).unwrap();
+        let mut signer = Signer::new(MessageDigest::sha256(), &key).unwrap();
+        signer.update(message.as_bytes()).unwrap();
+        let mac = signer.sign_to_vec().unwrap();
+        mac.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    pub fn link(&self, config: &Config, user_id: i64, email: &str) -> String {
+        let expires_at = Utc::now().timestamp() + LINK_LIFETIME;
+        format!(
+            "{}/verify_email/{}/{}/{}",
+            config.base_url(),
+            user_id,
+            expires_at,
+            self.signature(user_id, email, expires_at)
+        )
+    }
+
+    pub fn check(&self, user_id: i64, email: &str, expires_at: i64, signature: &str) -> bool {
+        expires_at > Utc::now().timestamp()
+            && tokens_match(&self.signature(user_id, email, expires_at), signature)
+    }
+}
+
+pub fn email(config: &Config, link: &str, username: &str, to: &str) -> Email {
+    Email {
+        to: to.to_string(),
+        subject: format!("Verify your email on {}", config.server_host()),
+        body: format!(
+            "Welcome to {}, {}!\n\n\
+             To confirm this is your address, open this link within a week:\n{}\n\n\
+             If you didn't sign up, you can ignore this email.\n",
+            config.server_host(),
+            username,
+            link
+        ),
+    }
+}
+
+/// The user's address, and whether it has been verified
+pub fn status(conn: &Connection, user_id: &str) -> rusqlite::Result<Option<(String, bool)>> {
+    let mut stmt = conn.prepare_cached(
+        r#"
+        SELECT email, email_verified_at IS NOT NULL FROM user
+        WHERE id = (?1)
+        "#,
+    )?;
+    let row: Option<(Option<String>, bool)> = stmt
+        .query_row(&[user_id], |row| Ok((row.get(0)?, row.get(1)?)))
+        .optional()?;
+    Ok(row.and_then(|(email, verified)| Some((email?, verified))))
+}
+
+/// Only true if the address is still the one the link was sent to
+pub fn mark_verified(conn: &Connection, user_id: i64, email: &str) -> rusqlite::Result<bool> {
+    let updated = conn.execute(
+        r#"
+        UPDATE user SET email_verified_at = strftime('%s', 'now')
+        WHERE id = (?1) AND email = (?2) AND email_verified_at IS NULL
+        "#,
+        params![user_id, email],
+    )?;
+    Ok(updated > 0)
+}
+
+/// Whether the user may write files. With verify_email off, everyone may
+pub fn can_publish(conn: &Connection, user_id: &str, config: &Config) -> rusqlite::Result<bool> {
+    if !config.verify_email || config.unverified_can_publish {
+        return Ok(true);
+    }
+    Ok(status(conn, user_id)?.map_or(false, |(_, verified)| verified))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_links() {
+        let signer = LinkSigner::new(&[7; 32]);
+        let expires_at = Utc::now().timestamp() + 60;
+        let signature = signer.signature(3, "a@example.org", expires_at);
+        assert!(signer.check(3, "a@example.org", expires_at, &signature));
+        assert!(!signer.check(4, "a@example.org", expires_at, &signature));
+        assert!(!signer.check(3, "b@example.org", expires_at, &signature));
+        assert!(!signer.check(3, "a@example.org", expires_at + 1, &signature));
+        assert!(!LinkSigner::new(&[8; 32]).check(3, "a@example.org", expires_at, &signature));
+
+        let expired = Utc::now().timestamp() - 1;
+        let signature = signer.signature(3, "a@example.org", expired);
+        assert!(!signer.check(3, "a@example.org", expired, &signature));
+    }
+}